@@ -65,11 +65,34 @@ start:
 .init_page_tables:
     ; Used to be manual, removed in commit 160
 
-.set_paging:
-    ; And set up paging
+.check_la57:
+    ; Use 5-level paging if the CPU supports it
+    mov eax, 0
+    cpuid
+    cmp eax, 7
+    jb .set_paging_4
+
+    mov eax, 7
+    mov ecx, 0
+    cpuid
+    test ecx, 1 << 16 ; LA57
+    jz .set_paging_4
+
+.set_paging_5:
+    mov eax, PML5  ; PML5 pointer
+    mov cr3, eax
+
+    mov eax, cr4
+    or eax, 1 << 12 ; LA57, has to be set before paging is enabled
+    mov cr4, eax
+    jmp .set_paging
+
+.set_paging_4:
     mov eax, PML4  ; PML4 pointer
     mov cr3, eax
 
+.set_paging:
+    ; And set up paging
     mov eax, cr4
     or eax, 3 << 4  ; Enable PAE and huge pages
     ; or eax, 3 << 20 ; Enable SMEP and SMAP ; turns out this is not well supported
@@ -88,7 +111,7 @@ start:
 
     mov eax, cr4
     or eax, 1 << 7  ; PGE for global pages
-    ; or eax, 1 << 17 ; PCIDE
+    ; PCIDE can only be set in long mode, see pcid::init
     mov cr4, eax
 
 .enable_fpu:
@@ -145,6 +168,10 @@ start_higher_half:
     add rax, boot_p3_mapping
     mov qword [rax], 0
 
+    mov rax, 0xFFFFFFFF80000000
+    add rax, boot_p5_mapping
+    mov qword [rax], 0
+
     mov eax, 0
     mov ds, eax
     mov es, eax
@@ -212,6 +239,7 @@ int_stack:
 int_stack_top:
 
 global tss64.stack
+global tss64.ist
     
 section .data
 tss64:
@@ -221,22 +249,8 @@ tss64:
     dq 0              ; stack pl1
     dq 0              ; stack pl2
     dq 0              ; reserved 0
-.ist0:
-    dq 0
-.ist1:
-    dq 0
-.ist2:
-    dq 0
-.ist3:
-    dq 0
-.ist4:
-    dq 0
-.ist5:
-    dq 0
-.ist6:
-    dq 0
-.ist7:
-    dq 0
+.ist:                 ; ist1-7, filled in by x86::ist_init
+    times 7 dq 0
     dq 0              ; reserved 0
     dw 0              ; reserved 0
 .iomap:
@@ -274,18 +288,21 @@ gdt64:
     db KERNEL_DATA
     db LONG_MODE
     db 0            ; segment base (ignored)
-.usrcode:
+.usrdata:
+    ; sysret takes user SS from user_base + 8 and CS from user_base + 16,
+    ; so user data has to come right before user code.
+
     dw 0            ; segment limit (ignored)
     dw 0            ; segment base (ignored)
     db 0            ; segment base (ignored)
-    db USER_CODE
+    db USER_DATA
     db LONG_MODE
     db 0            ; segment base (ignored)
-.usrdata:
+.usrcode:
     dw 0            ; segment limit (ignored)
     dw 0            ; segment base (ignored)
     db 0            ; segment base (ignored)
-    db USER_DATA
+    db USER_CODE
     db LONG_MODE
     db 0            ; segment base (ignored)
 .tssdesc: equ $ - gdt64
@@ -323,6 +340,7 @@ align 0x1000
 %define PAGE_FLAGS (PAGE_PRESENT | PAGE_WRITEABLE)
 
 global boot_pt_root
+global boot_p5_mapping
 global boot_p4_mapping
 global boot_p3_mapping
 boot_pt_root:
@@ -391,6 +409,14 @@ PHYS_PDPT:
     dq 0 + PAGE_PRESENT | PAGE_WRITEABLE | PAGE_ISHUGE | PAGE_GLOBAL
     times 511 dq 0
 
+; Only used with 5-level paging. Both the lower and higher half point at
+; the same PML4, so the kernel is at the same addresses in either mode.
+PML5:
+boot_p5_mapping:
+    dq PML4 + PAGE_FLAGS
+    times 510 dq 0
+    dq PML4 + PAGE_FLAGS
+
 section .text
 global read_ip
 read_ip:
@@ -55,11 +55,22 @@ asm_disable_irqs:
     cli
     ret
 
+global asm_read_cr0
+asm_read_cr0:
+    mov rax, cr0
+    ret
+
 global asm_read_cr2
 asm_read_cr2:
     mov rax, cr2
     ret
 
+; rbp is not touched on the way in, so this is the caller's frame pointer
+global asm_read_rbp
+asm_read_rbp:
+    mov rax, rbp
+    ret
+
 global asm_pause
 asm_pause:
     hlt
@@ -75,3 +86,81 @@ extern _kernel_phy_end
 asm_kernel_end:
     mov rax, _kernel_phy_end
     ret
+
+global asm_read_cr4
+asm_read_cr4:
+    mov rax, cr4
+    ret
+
+global asm_read_cr3
+asm_read_cr3:
+    mov rax, cr3
+    ret
+
+global asm_write_cr3
+asm_write_cr3:
+    mov cr3, rdi
+    ret
+
+global asm_invlpg
+asm_invlpg:
+    invlpg [rdi]
+    ret
+
+;; asm_cpuid(leaf, subleaf, *mut CpuidResult)
+global asm_cpuid
+asm_cpuid:
+    push rbx
+    mov r8, rdx
+    mov eax, edi
+    mov ecx, esi
+    cpuid
+    mov [r8], eax
+    mov [r8 + 4], ebx
+    mov [r8 + 8], ecx
+    mov [r8 + 12], edx
+    pop rbx
+    ret
+
+global asm_write_cr4
+asm_write_cr4:
+    mov cr4, rdi
+    ret
+
+;; asm_invpcid(type, *const [u64; 2])
+global asm_invpcid
+asm_invpcid:
+    invpcid rdi, [rsi]
+    ret
+
+global asm_read_flags
+asm_read_flags:
+    pushfq
+    pop rax
+    ret
+
+;; asm_read_msr(msr) -> value
+global asm_read_msr
+asm_read_msr:
+    mov ecx, edi
+    rdmsr
+    shl rdx, 32
+    or rax, rdx
+    ret
+
+;; asm_write_msr(msr, value)
+global asm_write_msr
+asm_write_msr:
+    mov ecx, edi
+    mov eax, esi
+    mov rdx, rsi
+    shr rdx, 32
+    wrmsr
+    ret
+
+global asm_read_tsc
+asm_read_tsc:
+    rdtsc
+    shl rdx, 32
+    or rax, rdx
+    ret
@@ -67,10 +67,10 @@ return_from_interrupt:
 global asm_jmp_to_user 
 asm_jmp_to_user:
     ;; TODO: 0 GPRs to not leak kernel data
-    push 0x20 | 3   ;; SS
+    push 0x18 | 3   ;; SS
     push rsi        ;; RSP
     push 0x200      ;; RFLAGS (IF)
-    push 0x18 | 3   ;; CS
+    push 0x20 | 3   ;; CS
     push rdi        ;; RIP
     mov rdi, rdx    ;; user arg 1
     mov rsi, rcx    ;; user arg 2
@@ -139,6 +139,7 @@ global irq14
 global irq15
 global isr_syscall
 global isr_panic
+global isr_spurious
 
 isr0: isrnoerr 0
 isr1: isrnoerr 1
@@ -190,6 +191,7 @@ irq14: isrnoerr 46
 irq15: isrnoerr 47
 isr_syscall: isrnoerr 128
 isr_panic: isrnoerr 130
+isr_spurious: isrnoerr 255
 
 ;%assign isr_num 48
 ;%rep 208
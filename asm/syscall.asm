@@ -0,0 +1,101 @@
+; vim: syntax=nasm :
+
+;; The SYSCALL entry point. SYSCALL leaves the user rip in rcx and rflags in
+;; r11, and doesn't switch stacks, so this moves to the thread's kernel stack
+;; and builds the same frame interrupt_shim does. The kernel side then
+;; doesn't care whether a syscall came in this way or through int 0x80.
+
+extern c_syscall_shim
+extern kernel_stack
+extern return_from_interrupt
+
+%define USER_SS (0x18 | 3)
+%define USER_CS (0x20 | 3)
+%define SYSCALL_VECTOR 0x80
+
+;; Offset of the saved rip in the frame
+%define FRAME_IP (18 * 8)
+
+section .bss
+
+;; Interrupts are off until the user stack pointer is on the kernel stack,
+;; so one slot is enough
+user_rsp:
+    resq 1
+
+section .text
+
+global syscall_entry
+syscall_entry:
+    mov [user_rsp], rsp
+    mov rsp, [kernel_stack]
+
+    push USER_SS
+    push qword [user_rsp]
+    push r11        ;; RFLAGS
+    push USER_CS
+    push rcx        ;; RIP
+    push 0          ;; error code
+    push SYSCALL_VECTOR
+
+    push rax
+    push rcx
+    push rbx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov ebp, ds
+    push rbp     ; push data segment
+
+    mov ebp, 0
+    mov ds, ebp  ; set kernel data segment
+
+    sti
+    mov rdi, rsp
+    mov rax, c_syscall_shim
+    call rax
+    cli
+
+    ;; sysret to a non-canonical address faults in kernel mode on some CPUs,
+    ;; so let iretq deal with that case
+    mov rcx, [rsp + FRAME_IP]
+    mov r11, rcx
+    shl r11, 16
+    sar r11, 16
+    cmp r11, rcx
+    jne return_from_interrupt
+
+    pop rbp
+    mov ds, ebp ; restore data segment
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rbx
+    pop rcx
+    pop rax
+    add rsp, 16     ;; vector and error code
+
+    mov rcx, [rsp]          ;; RIP
+    mov r11, [rsp + 16]     ;; RFLAGS
+    mov rsp, [rsp + 24]     ;; RSP
+    o64 sysret
@@ -0,0 +1,26 @@
+; vim: syntax=nasm :
+
+;; Instructions that touch user memory on behalf of the kernel. Every
+;; instruction that may fault has an entry in .ex_table pairing it with a
+;; fixup address. The page fault handler looks up the faulting ip there and
+;; resumes at the fixup instead of panicking.
+
+section .text
+
+;; asm_copy_user(dst, src, len) -> bytes not copied
+;; rdi: destination
+;; rsi: source
+;; rdx: length
+global asm_copy_user
+asm_copy_user:
+    mov rcx, rdx
+.copy:
+    rep movsb
+    xor eax, eax
+    ret
+.fault:
+    mov rax, rcx    ;; rep movsb leaves the remaining count in rcx
+    ret
+
+section .ex_table alloc noexec nowrite align=8
+    dq asm_copy_user.copy, asm_copy_user.fault
@@ -24,7 +24,7 @@ def backtrace
   output = `tail -n100 last_output`
   output.split("\n").map do |l|
     case l
-    when /\((.*)\) <.*>/
+    when /\((0x\h+)\) <.*>/
       $1
     when /bp:.*ip: (.+)$/
       $1
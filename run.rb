@@ -12,6 +12,7 @@ OptionParser.new do |opts|
   opts.on("-f", "--file FILE", "ISO to boot") { |f| options[:file] = f }
   opts.on("-r", "--ram RAM", "Set emulated machine RAM") { |r| options[:ram] = r }
   opts.on("-d", "--debug", "Wait for GDB connection") { options[:debug] = true }
+  opts.on("-g", "--gdb-stub", "Attach COM3 to ./gdb for the in-kernel GDB stub") { options[:gdb_stub] = true }
   opts.on("-v", "--video", "Enable QEMU graphical video output") { options[:video] = true }
   opts.on("-i", "--interrupts", "Enable QEMU interrupt debugging") { options[:interrupts] = true }
   opts.on("-n", "--no-serial", "Do not use serial stdio") { options[:serial] = false }
@@ -43,6 +44,7 @@ qemu_command << "-d int" if options[:interrupts]
 qemu_command << "-display none" unless options[:video]
 qemu_command << "--device isa-debug-exit" if options[:test]
 qemu_command << "-serial unix:./serial2,nowait,server"
+qemu_command << "-serial unix:./gdb,nowait,server" if options[:gdb_stub]
 
 if options[:net]
   qemu_command << "-device rtl8139,netdev=net0"
@@ -0,0 +1,138 @@
+//! Just enough ACPI to find tables: the RSDP is found by scanning the BIOS
+//! areas, and tables are looked up by signature in the RSDT or XSDT.
+//!
+//! Tables are read through the direct map, so ones above it are skipped.
+
+use crate::memory::{direct_map_end, PhysicalAddress};
+use core::mem::size_of;
+use core::slice;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The real mode segment of the extended BIOS data area is stored here
+const EBDA_SEGMENT: usize = 0x40E;
+const EBDA_SEARCH_SIZE: usize = 1024;
+const BIOS_AREA_START: usize = 0xE_0000;
+const BIOS_AREA_END: usize = 0x10_0000;
+
+/// The RSDP is always on a 16 byte boundary
+const RSDP_ALIGN: usize = 16;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Revision 2 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// The revision 0 part of the RSDP that the checksum covers
+const RSDP_V1_SIZE: usize = 20;
+
+/// The header every system description table starts with
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Whether `len` bytes at `p` can be read through the direct map
+fn readable(p: usize, len: usize) -> bool {
+    p.checked_add(len)
+        .map_or(false, |end| end <= direct_map_end())
+}
+
+/// The bytes at `p`, which must be readable
+unsafe fn bytes(p: usize, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(PhysicalAddress(p).direct_map() as *const u8, len)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn scan_for_rsdp(start: usize, end: usize) -> Option<Rsdp> {
+    for p in (start..end).step_by(RSDP_ALIGN) {
+        if !readable(p, size_of::<Rsdp>()) {
+            return None;
+        }
+        let rsdp: Rsdp = unsafe { PhysicalAddress(p).read_phy() };
+        if &rsdp.signature == RSDP_SIGNATURE
+            && checksum_ok(unsafe { bytes(p, RSDP_V1_SIZE) })
+        {
+            return Some(rsdp);
+        }
+    }
+    None
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda_segment: u16 = unsafe { PhysicalAddress(EBDA_SEGMENT).read_phy() };
+    let ebda = (ebda_segment as usize) << 4;
+    let in_ebda = if ebda != 0 {
+        scan_for_rsdp(ebda, ebda + EBDA_SEARCH_SIZE)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| scan_for_rsdp(BIOS_AREA_START, BIOS_AREA_END))
+}
+
+/// The whole table at `p`, if it is readable and its checksum is good
+fn table_at(p: usize) -> Option<&'static [u8]> {
+    if !readable(p, size_of::<SdtHeader>()) {
+        return None;
+    }
+    let header: SdtHeader = unsafe { PhysicalAddress(p).read_phy() };
+    let length = header.length as usize;
+    if length < size_of::<SdtHeader>() || !readable(p, length) {
+        return None;
+    }
+    let table = unsafe { bytes(p, length) };
+    if checksum_ok(table) {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+/// The physical addresses of every table the RSDT or XSDT points to.
+fn table_addresses() -> impl Iterator<Item = usize> {
+    let rsdp = find_rsdp();
+    // The XSDT has 64-bit pointers and takes precedence when there is one
+    let (root, entry_size) = match rsdp {
+        Some(rsdp) if rsdp.revision >= 2 && rsdp.xsdt_address != 0 => {
+            (table_at(rsdp.xsdt_address as usize), 8)
+        }
+        Some(rsdp) => (table_at(rsdp.rsdt_address as usize), 4),
+        None => (None, 4),
+    };
+    let entries = root.map_or(&[][..], |root| &root[size_of::<SdtHeader>()..]);
+    entries.chunks_exact(entry_size).map(|entry| {
+        entry
+            .iter()
+            .rev()
+            .fold(0usize, |address, &b| address << 8 | b as usize)
+    })
+}
+
+/// The first table with `signature`, header included.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    table_addresses()
+        .filter_map(table_at)
+        .find(|table| &table[..4] == signature)
+}
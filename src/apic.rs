@@ -0,0 +1,326 @@
+//! The local APIC, which delivers interrupts to this CPU, and the I/O
+//! APICs, which route device interrupts to it. When there is an APIC it
+//! replaces the PIC: ISA IRQs are routed through the I/O APIC to the same
+//! vectors the PIC used, following the interrupt source overrides in the
+//! ACPI MADT.
+
+use crate::interrupt::{IRQ_BASE, IRQ_COUNT};
+use crate::memory::{self, PhysicalRange};
+use crate::sync::Mutex;
+use crate::{acpi, x86};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: usize = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: usize = 0x000F_FFFF_FFFF_F000;
+
+const LAPIC_SIZE: usize = 0x400;
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+
+/// Set in the spurious vector register to enable the local APIC
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// The local APIC sends this when an interrupt goes away before the CPU
+/// takes it. It needs no EOI.
+pub const SPURIOUS_VECTOR: usize = 0xFF;
+
+const IO_APIC_SIZE: usize = 0x20;
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DESTINATION_SHIFT: u64 = 56;
+
+/// Where the I/O APIC is on a PC, if there's no MADT to say
+const DEFAULT_IO_APIC: usize = 0xFEC0_0000;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+/// The local APIC address and flags after the header
+const MADT_ENTRIES: usize = size_of::<acpi::SdtHeader>() + 8;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_SOURCE_OVERRIDE: u8 = 2;
+
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b11 << 2;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The virtual address of the local APIC registers
+static LAPIC: AtomicUsize = AtomicUsize::new(0);
+
+struct IoApic {
+    /// The virtual address of the registers
+    base: usize,
+    /// The first global system interrupt this I/O APIC handles
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn new(address: usize, gsi_base: u32) -> Option<IoApic> {
+        let range = PhysicalRange::new(address, address + IO_APIC_SIZE);
+        let base = memory::map_mmio(range).ok()?.0;
+        let mut io_apic = IoApic {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xFF) + 1;
+        Some(io_apic)
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            ptr::read_volatile((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn write_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION + 2 * (gsi - self.gsi_base);
+        // Mask the entry while it's half written
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// Where an ISA IRQ arrives at the I/O APICs
+#[derive(Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    active_low: bool,
+    level: bool,
+}
+
+struct Routing {
+    io_apics: Vec<IoApic>,
+    /// From the MADT's interrupt source overrides
+    overrides: [Option<IsaRoute>; IRQ_COUNT],
+}
+
+impl Routing {
+    const fn new() -> Self {
+        Self {
+            io_apics: Vec::new(),
+            overrides: [None; IRQ_COUNT],
+        }
+    }
+
+    /// ISA IRQs without an override are identity mapped, active high and
+    /// edge triggered.
+    fn isa_route(&self, irq: usize) -> IsaRoute {
+        self.overrides[irq].unwrap_or(IsaRoute {
+            gsi: irq as u32,
+            active_low: false,
+            level: false,
+        })
+    }
+
+    fn io_apic_for(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics.iter().find(|io_apic| io_apic.handles(gsi))
+    }
+
+    fn set_masked(&self, irq: usize, masked: bool) {
+        let route = self.isa_route(irq);
+        let io_apic = match self.io_apic_for(route.gsi) {
+            Some(io_apic) => io_apic,
+            None => {
+                warn!("no I/O APIC handles irq {} (gsi {})", irq, route.gsi);
+                return;
+            }
+        };
+        let mut entry = (IRQ_BASE + irq) as u64
+            | (id() as u64) << REDIRECTION_DESTINATION_SHIFT;
+        if route.active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if route.level {
+            entry |= REDIRECTION_LEVEL;
+        }
+        if masked {
+            entry |= REDIRECTION_MASKED;
+        }
+        io_apic.write_redirection(route.gsi, entry);
+    }
+
+    /// Fill in the I/O APICs and overrides from the MADT. Returns the
+    /// number of processors it lists.
+    fn parse_madt(&mut self, madt: &[u8]) -> usize {
+        let mut cpus = 0;
+        let mut entries = &madt[MADT_ENTRIES.min(madt.len())..];
+        while entries.len() >= 2 {
+            let (kind, length) = (entries[0], entries[1] as usize);
+            if length < 2 || length > entries.len() {
+                break;
+            }
+            let entry = &entries[..length];
+            match kind {
+                MADT_LOCAL_APIC if length >= 8 => {
+                    // Only enabled processors count
+                    if read_u32(entry, 4) & 1 != 0 {
+                        cpus += 1;
+                    }
+                }
+                MADT_IO_APIC if length >= 12 => {
+                    let address = read_u32(entry, 4) as usize;
+                    let gsi_base = read_u32(entry, 8);
+                    match IoApic::new(address, gsi_base) {
+                        Some(io_apic) => self.io_apics.push(io_apic),
+                        None => {
+                            warn!("can't map I/O APIC at {:#x}", address);
+                        }
+                    }
+                }
+                MADT_SOURCE_OVERRIDE if length >= 10 => {
+                    let source = entry[3] as usize;
+                    let flags = read_u16(entry, 8);
+                    if source < IRQ_COUNT {
+                        self.overrides[source] = Some(IsaRoute {
+                            gsi: read_u32(entry, 4),
+                            active_low: flags & POLARITY_MASK
+                                == POLARITY_ACTIVE_LOW,
+                            level: flags & TRIGGER_MASK == TRIGGER_LEVEL,
+                        });
+                    }
+                }
+                _ => {}
+            }
+            entries = &entries[length..];
+        }
+        cpus
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+/// Only locked with interrupts disabled
+static ROUTING: Mutex<Routing> = Mutex::new(Routing::new());
+
+fn lapic_read(register: usize) -> u32 {
+    let base = LAPIC.load(Ordering::Relaxed);
+    unsafe { ptr::read_volatile((base + register) as *const u32) }
+}
+
+fn lapic_write(register: usize, value: u32) {
+    let base = LAPIC.load(Ordering::Relaxed);
+    unsafe { ptr::write_volatile((base + register) as *mut u32, value) }
+}
+
+fn lapic_init() -> bool {
+    let msr = x86::read_msr(IA32_APIC_BASE);
+    let address = msr & APIC_BASE_ADDRESS_MASK;
+    let range = PhysicalRange::new(address, address + LAPIC_SIZE);
+    let base = match memory::map_mmio(range) {
+        Ok(base) => base.0,
+        Err(error) => {
+            warn!("can't map the local APIC at {:#x}: {:?}", address, error);
+            return false;
+        }
+    };
+    unsafe { x86::write_msr(IA32_APIC_BASE, msr | APIC_BASE_ENABLE) };
+    LAPIC.store(base, Ordering::Relaxed);
+
+    lapic_write(LAPIC_TPR, 0);
+    lapic_write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    true
+}
+
+/// Switch from the PIC to the APIC if the machine has one. Every IRQ
+/// starts masked, as it does on the PIC after pic_init, so this has to run
+/// before any IRQ handlers are registered.
+pub fn init() -> bool {
+    if !x86::has_apic() {
+        return false;
+    }
+
+    let mut routing = Routing::new();
+    match acpi::find_table(MADT_SIGNATURE) {
+        Some(madt) => {
+            let cpus = routing.parse_madt(madt);
+            info!("MADT lists {} processors", cpus);
+        }
+        None => {
+            warn!("no MADT, assuming an I/O APIC at {:#x}", DEFAULT_IO_APIC);
+            routing.io_apics.extend(IoApic::new(DEFAULT_IO_APIC, 0));
+        }
+    }
+    if routing.io_apics.is_empty() {
+        warn!("no usable I/O APIC, staying on the PIC");
+        return false;
+    }
+    if !lapic_init() {
+        return false;
+    }
+
+    for irq in 0..IRQ_COUNT {
+        routing.set_masked(irq, true);
+    }
+    for io_apic in &routing.io_apics {
+        info!(
+            "I/O APIC: gsi {}..{}",
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.entries
+        );
+    }
+    x86::without_interrupts(|| *ROUTING.lock() = routing);
+    x86::pic_disable();
+    ENABLED.store(true, Ordering::Relaxed);
+    info!("local APIC {} enabled", id());
+    true
+}
+
+/// Whether init switched to the APIC
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The local APIC ID of this CPU
+pub fn id() -> usize {
+    (lapic_read(LAPIC_ID) >> 24) as usize
+}
+
+/// Signal the end of the interrupt being handled.
+pub fn eoi() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+pub fn unmask_irq(irq: usize) {
+    x86::without_interrupts(|| ROUTING.lock().set_masked(irq, false));
+}
+
+pub fn mask_irq(irq: usize) {
+    x86::without_interrupts(|| ROUTING.lock().set_masked(irq, true));
+}
@@ -0,0 +1,124 @@
+//! Frame-pointer backtraces, symbolized with the table the makefile builds
+//! from `nm` and grub loads as the "symbols" multiboot module.
+//!
+//! This runs from the panic handler and fault paths, so it takes no locks,
+//! doesn't allocate, and checks every frame is mapped before reading it.
+
+use crate::memory::{PageTable, VirtualAddress};
+use crate::sync::Once;
+use crate::x86;
+use core::mem::size_of;
+use core::str;
+
+/// Stop after this many frames, in case the chain loops
+const MAX_FRAMES: usize = 64;
+
+/// `nm -n` output: sorted lines of "<hex address> <type> <name>"
+static SYMBOLS: Once<&'static [u8]> = Once::new();
+
+pub fn load_symbols(table: &'static [u8]) {
+    SYMBOLS.call_once(|| table);
+    let count = table.split(|&b| b == b'\n').count();
+    info!("loaded {} symbols", count);
+}
+
+fn parse_symbol(line: &[u8]) -> Option<(usize, &str)> {
+    let line = str::from_utf8(line).ok()?;
+    let mut parts = line.splitn(3, ' ');
+    let addr = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let _kind = parts.next()?;
+    let name = parts.next()?;
+    Some((addr, name))
+}
+
+/// The symbol containing `addr` and the offset into it.
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    let table = SYMBOLS.get()?;
+    let mut best = None;
+    for line in table.split(|&b| b == b'\n') {
+        match parse_symbol(line) {
+            Some((start, _)) if start > addr => break,
+            Some((start, name)) => best = Some((name, addr - start)),
+            None => continue,
+        }
+    }
+    best
+}
+
+/// Whether a frame record at `bp` can be read without faulting.
+fn frame_readable(bp: usize) -> bool {
+    if bp == 0 || bp % size_of::<usize>() != 0 {
+        return false;
+    }
+    let table = PageTable::current();
+    let last = bp.wrapping_add(2 * size_of::<usize>() - 1);
+    table.translate(VirtualAddress(bp)).is_some()
+        && table.translate(VirtualAddress(last)).is_some()
+}
+
+/// Call `f` with the return address of each frame in the chain starting
+/// at `bp`. The chain is rooted by a zero frame in boot.asm and in each
+/// new thread's stack.
+pub fn walk(mut bp: usize, mut f: impl FnMut(usize)) {
+    for _ in 0..MAX_FRAMES {
+        if !frame_readable(bp) {
+            return;
+        }
+        let frame = bp as *const usize;
+        let (next, ip) = unsafe { (*frame, *frame.add(1)) };
+        if ip == 0 {
+            return;
+        }
+        f(ip);
+        // Stacks grow down, so a frame further down the chain that isn't
+        // higher up the stack means it's corrupt.
+        if next <= bp {
+            return;
+        }
+        bp = next;
+    }
+}
+
+/// Print one line of a backtrace. The address is written as "(addr) <"
+/// so `dump.rb -a` can still pick it out.
+fn print_frame(index: usize, ip: usize, lookup: usize) {
+    match symbolize(lookup) {
+        Some((name, offset)) => {
+            let offset = offset + (ip - lookup);
+            dprintln!(
+                "  {:>2}: ({:#018x}) <{}+{:#x}>",
+                index,
+                ip,
+                name,
+                offset
+            );
+        }
+        None => {
+            dprintln!("  {:>2}: ({:#018x}) <?>", index, ip);
+        }
+    }
+}
+
+/// Print the frames starting at `bp`. Return addresses point after the
+/// call, which may be past the end of the calling function, so they are
+/// looked up one byte back.
+fn print_chain(bp: usize, mut index: usize) {
+    walk(bp, |ip| {
+        print_frame(index, ip, ip - 1);
+        index += 1;
+    });
+}
+
+/// Print a backtrace of the calling code.
+pub fn print() {
+    dprintln!("backtrace:");
+    print_chain(x86::read_rbp(), 0);
+}
+
+/// Print a backtrace of code that was interrupted at `ip` with frame
+/// pointer `bp`, from an exception handler.
+pub fn print_interrupted(ip: usize, bp: usize) {
+    dprintln!("backtrace:");
+    print_frame(0, ip, ip);
+    print_chain(bp, 1);
+}
@@ -0,0 +1,106 @@
+use crate::memory::{PhysicalPage, PAGE_SIZE};
+use crate::phy_map;
+use crate::util::round_up;
+use alloc::vec::Vec;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    OutOfRange,
+    BadBufferSize,
+    Device,
+}
+
+/// A device that reads and writes whole fixed-size blocks. Buffers passed
+/// to `read` and `write` must be a multiple of the block size long.
+pub trait BlockDevice: Send {
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> usize;
+    fn read(&mut self, block: usize, buf: &mut [u8]) -> Result<(), BlockError>;
+    fn write(&mut self, block: usize, buf: &[u8]) -> Result<(), BlockError>;
+
+    fn len(&self) -> usize {
+        self.block_size() * self.block_count()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A block device stored in physical pages. Handy as a stand-in for a real
+/// disk, for instance to back swap while testing.
+#[derive(Debug)]
+pub struct RamDisk {
+    pages: Vec<PhysicalPage>,
+}
+
+impl RamDisk {
+    pub const BLOCK_SIZE: usize = 512;
+
+    pub fn new(size: usize) -> Self {
+        let count = round_up(size, PAGE_SIZE) / PAGE_SIZE;
+        let pages = (0..count).map(|_| phy_map::alloc_zero().page()).collect();
+        RamDisk { pages }
+    }
+
+    fn check(&self, block: usize, len: usize) -> Result<(), BlockError> {
+        if len % Self::BLOCK_SIZE != 0 {
+            return Err(BlockError::BadBufferSize);
+        }
+        if block * Self::BLOCK_SIZE + len > self.len() {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// A pointer to the direct-mapped memory backing byte `offset` of the
+    /// disk. The `len` bytes from there must not cross a page boundary.
+    fn chunk(&self, offset: usize, len: usize) -> *mut u8 {
+        let page = self.pages[offset / PAGE_SIZE];
+        let address = page.base_address() + offset % PAGE_SIZE;
+        debug_assert!(offset % PAGE_SIZE + len <= PAGE_SIZE);
+        address.direct_map() as *mut u8
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        Self::BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> usize {
+        self.pages.len() * PAGE_SIZE / Self::BLOCK_SIZE
+    }
+
+    fn read(&mut self, block: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check(block, buf.len())?;
+        let start = block * Self::BLOCK_SIZE;
+        for (i, out) in buf.chunks_mut(Self::BLOCK_SIZE).enumerate() {
+            let src = self.chunk(start + i * Self::BLOCK_SIZE, out.len());
+            unsafe {
+                core::ptr::copy_nonoverlapping(src, out.as_mut_ptr(), out.len())
+            };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, block: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.check(block, buf.len())?;
+        let start = block * Self::BLOCK_SIZE;
+        for (i, input) in buf.chunks(Self::BLOCK_SIZE).enumerate() {
+            let dst = self.chunk(start + i * Self::BLOCK_SIZE, input.len());
+            unsafe {
+                core::ptr::copy_nonoverlapping(input.as_ptr(), dst, input.len())
+            };
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RamDisk {
+    fn drop(&mut self) {
+        for page in &self.pages {
+            phy_map::free(page.base_address());
+        }
+    }
+}
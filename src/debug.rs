@@ -1,9 +1,9 @@
 use core::fmt::{self, Write};
 
-use crate::serial::SerialPort;
+use crate::serial::PolledWriter;
 
 pub fn print(args: fmt::Arguments) {
-    SerialPort::new(0x3f8).write_fmt(args).unwrap();
+    PolledWriter::new(0x3f8).write_fmt(args).unwrap();
 }
 
 #[macro_export]
@@ -0,0 +1,403 @@
+//! Loading ELF64 executables into a new address space to run in user mode.
+//! Only static x86_64 executables are supported, since there is no dynamic
+//! linker to hand a PT_INTERP program to.
+//!
+//! Executables come from multiboot modules, which are registered by name
+//! at boot and run as processes.
+
+use crate::memory::{
+    self, AddressSpace, PageFlags, PagingError, VirtualAddress, VirtualRange,
+    PAGE_SIZE,
+};
+use crate::shell::{self, Command};
+use crate::sync::Mutex;
+use crate::util::round_down;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u32 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// Auxiliary vector entries, as on Linux
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+const STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+/// How much of the stack argv, envp and the auxiliary vector can use.
+/// The rest is left for the program.
+const STACK_ARGUMENTS_MAX: usize = STACK_SIZE / 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,
+    NotElf,
+    Not64Bit,
+    NotLittleEndian,
+    BadVersion,
+    NotExecutable,
+    WrongMachine,
+    NeedsInterpreter,
+    BadSegment,
+    BadEntry,
+    ArgumentsTooLarge,
+    Paging(PagingError),
+}
+
+impl From<PagingError> for ElfError {
+    fn from(error: PagingError) -> Self {
+        ElfError::Paging(error)
+    }
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => f.write_str("file is truncated"),
+            ElfError::NotElf => f.write_str("not an ELF file"),
+            ElfError::Not64Bit => f.write_str("not a 64 bit ELF file"),
+            ElfError::NotLittleEndian => f.write_str("not little endian"),
+            ElfError::BadVersion => f.write_str("unknown ELF version"),
+            ElfError::NotExecutable => f.write_str("not an executable"),
+            ElfError::WrongMachine => f.write_str("not an x86_64 program"),
+            ElfError::NeedsInterpreter => f.write_str("needs a dynamic linker"),
+            ElfError::BadSegment => f.write_str("bad program segment"),
+            ElfError::BadEntry => {
+                f.write_str("entry point is not in executable memory")
+            }
+            ElfError::ArgumentsTooLarge => {
+                f.write_str("arguments don't fit on the stack")
+            }
+            ElfError::Paging(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+impl ProgramHeader {
+    /// The file offsets of the segment's contents
+    fn file_range(&self) -> Option<(usize, usize)> {
+        let start = self.offset as usize;
+        Some((start, start.checked_add(self.filesz as usize)?))
+    }
+
+    fn page_flags(&self) -> PageFlags {
+        let mut flags = PageFlags::USERMODE;
+        if self.flags & PF_W != 0 {
+            flags |= PageFlags::WRITEABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Read a `T` at `offset` in `image`, which needn't be aligned.
+fn read<T: Copy>(image: &[u8], offset: usize) -> Result<T, ElfError> {
+    let end = offset
+        .checked_add(size_of::<T>())
+        .ok_or(ElfError::Truncated)?;
+    if end > image.len() {
+        return Err(ElfError::Truncated);
+    }
+    Ok(unsafe { ptr::read_unaligned(image[offset..].as_ptr() as *const T) })
+}
+
+fn header(image: &[u8]) -> Result<Header, ElfError> {
+    let header: Header = read(image, 0)?;
+    if &header.ident[..4] != MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    if header.ident[4] != CLASS_64 {
+        return Err(ElfError::Not64Bit);
+    }
+    if header.ident[5] != DATA_LITTLE_ENDIAN {
+        return Err(ElfError::NotLittleEndian);
+    }
+    if header.ident[6] as u32 != VERSION_CURRENT
+        || header.version != VERSION_CURRENT
+    {
+        return Err(ElfError::BadVersion);
+    }
+    if header.kind != TYPE_EXECUTABLE {
+        return Err(ElfError::NotExecutable);
+    }
+    if header.machine != MACHINE_X86_64 {
+        return Err(ElfError::WrongMachine);
+    }
+    if (header.phentsize as usize) < size_of::<ProgramHeader>() {
+        return Err(ElfError::Truncated);
+    }
+    Ok(header)
+}
+
+fn program_headers(
+    image: &[u8],
+    header: &Header,
+) -> Result<Vec<ProgramHeader>, ElfError> {
+    (0..header.phnum as usize)
+        .map(|i| {
+            let offset = (header.phoff as usize)
+                .checked_add(i * header.phentsize as usize)
+                .ok_or(ElfError::Truncated)?;
+            read(image, offset)
+        })
+        .collect()
+}
+
+/// Whether `image` starts like an ELF file. It may still fail to load.
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(MAGIC)
+}
+
+/// Map the pages of a PT_LOAD segment and fill them in. The part of the
+/// segment past the end of its file contents is .bss, and is zeroed.
+fn load_segment(
+    space: &mut AddressSpace,
+    image: &[u8],
+    ph: &ProgramHeader,
+) -> Result<(), ElfError> {
+    let (file_start, file_end) = ph.file_range().ok_or(ElfError::BadSegment)?;
+    if file_end > image.len() || ph.filesz > ph.memsz {
+        return Err(ElfError::BadSegment);
+    }
+    if ph.memsz == 0 {
+        return Ok(());
+    }
+    let start = ph.vaddr as usize;
+    let end = start
+        .checked_add(ph.memsz as usize)
+        .ok_or(ElfError::BadSegment)?;
+    if end > stack_bottom() {
+        return Err(ElfError::BadSegment);
+    }
+
+    let flags = ph.page_flags();
+    for page in VirtualRange::new(start, end).pages() {
+        match space.table().translate(page) {
+            // Segments can share a page where they meet, and then the page
+            // allows what either of them does
+            Some((_, existing)) => {
+                let mut merged = existing | flags;
+                if !(existing & flags).contains(PageFlags::NO_EXECUTE) {
+                    merged.remove(PageFlags::NO_EXECUTE);
+                }
+                space.table_mut().edit_flags(page, merged)?;
+            }
+            None => space.map_zeroed(page, flags)?,
+        }
+    }
+
+    let filesz = ph.filesz as usize;
+    space.write(VirtualAddress(start), &image[file_start..file_end])?;
+    space.fill(
+        VirtualAddress(start + filesz),
+        ph.memsz as usize - filesz,
+        0,
+    )?;
+    Ok(())
+}
+
+/// The user stack ends one page below the top of the lower half, and the
+/// page below it is left unmapped so an overflow faults.
+fn stack_top() -> usize {
+    memory::lower_half_end() - PAGE_SIZE
+}
+
+fn stack_bottom() -> usize {
+    stack_top() - STACK_SIZE - PAGE_SIZE
+}
+
+/// Map the user stack and lay out argc, argv, envp and the auxiliary
+/// vector at the top of it as the System V ABI describes. Returns the
+/// initial stack pointer, which points at argc.
+fn build_stack(
+    space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(usize, usize)],
+) -> Result<VirtualAddress, ElfError> {
+    let top = stack_top();
+    for page in VirtualRange::new(top - STACK_SIZE, top).pages() {
+        space.map_zeroed(
+            page,
+            PageFlags::USERMODE | PageFlags::WRITEABLE | PageFlags::NO_EXECUTE,
+        )?;
+    }
+
+    // The strings go at the very top, then the pointers to them below
+    let strings_len: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let words_len = 1 + argv.len() + 1 + envp.len() + 1 + 2 * auxv.len() + 2;
+    if strings_len + words_len * size_of::<usize>() > STACK_ARGUMENTS_MAX {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+    let strings = top - strings_len;
+    let sp = round_down(strings - words_len * size_of::<usize>(), 16);
+
+    let mut words = Vec::with_capacity(words_len);
+    let mut string = strings;
+    words.push(argv.len());
+    for list in &[argv, envp] {
+        for s in list.iter() {
+            words.push(string);
+            space.write(VirtualAddress(string), s.as_bytes())?;
+            // The stack is zeroed, so the terminating NUL is already there
+            string += s.len() + 1;
+        }
+        words.push(0);
+    }
+    for &(key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+    words.push(AT_NULL);
+    words.push(0);
+
+    for (i, word) in words.iter().enumerate() {
+        let v = VirtualAddress(sp + i * size_of::<usize>());
+        space.write(v, &word.to_le_bytes())?;
+    }
+    Ok(VirtualAddress(sp))
+}
+
+/// A program loaded into its own address space, ready to run
+#[derive(Debug)]
+pub struct Program {
+    pub space: AddressSpace,
+    pub entry: VirtualAddress,
+    pub stack_pointer: VirtualAddress,
+}
+
+/// Load the executable `image` into a new address space, with a stack
+/// holding `argv` and `envp`.
+pub fn load(
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<Program, ElfError> {
+    let header = header(image)?;
+    let headers = program_headers(image, &header)?;
+    if headers.iter().any(|ph| ph.kind == PT_INTERP) {
+        return Err(ElfError::NeedsInterpreter);
+    }
+
+    let mut space = AddressSpace::new()?;
+    for ph in headers.iter().filter(|ph| ph.kind == PT_LOAD) {
+        load_segment(&mut space, image, ph)?;
+    }
+
+    let entry = VirtualAddress(header.entry as usize);
+    match space.table().translate(entry) {
+        Some((_, flags))
+            if flags.contains(PageFlags::USERMODE)
+                && !flags.contains(PageFlags::NO_EXECUTE) => {}
+        _ => return Err(ElfError::BadEntry),
+    }
+
+    let mut auxv = Vec::new();
+    // The program headers are only in memory if a segment loaded them
+    let phoff = header.phoff as usize;
+    let phdr = headers.iter().find_map(|ph| {
+        let (start, end) = ph.file_range()?;
+        if ph.kind == PT_LOAD && start <= phoff && phoff < end {
+            Some(ph.vaddr as usize + (phoff - start))
+        } else {
+            None
+        }
+    });
+    if let Some(phdr) = phdr {
+        auxv.push((AT_PHDR, phdr));
+    }
+    auxv.push((AT_PHENT, header.phentsize as usize));
+    auxv.push((AT_PHNUM, header.phnum as usize));
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_ENTRY, entry.0));
+
+    let stack_pointer = build_stack(&mut space, argv, envp, &auxv)?;
+    Ok(Program {
+        space,
+        entry,
+        stack_pointer,
+    })
+}
+
+/// Executables from the boot modules, by name
+static PROGRAMS: Mutex<Vec<(String, &'static [u8])>> = Mutex::new(Vec::new());
+
+/// Make `image` available to run as `name`.
+pub fn add_program(name: &str, image: &'static [u8]) {
+    let mut programs = PROGRAMS.lock();
+    programs.retain(|(n, _)| n != name);
+    programs.push((name.to_string(), image));
+}
+
+pub fn find_program(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS
+        .lock()
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|&(_, image)| image)
+}
+
+pub fn register_commands() {
+    shell::register(Command {
+        name: "programs",
+        usage: "programs",
+        help: "list the programs from the boot modules",
+        run: |_| {
+            for (name, image) in PROGRAMS.lock().iter() {
+                println!("{:<16} {:>8} bytes", name, image.len());
+            }
+            Ok(())
+        },
+    });
+}
@@ -0,0 +1,775 @@
+//! A GDB remote serial protocol stub, so the kernel can be debugged over a
+//! serial port with `target remote`. Unlike QEMU's gdbstub this knows about
+//! kernel threads: each thread shows up in `info threads`, and its saved
+//! registers can be inspected with `thread N`.
+//!
+//! The stub runs inside the #DB and #BP handlers with interrupts disabled.
+//! It may have stopped the kernel while any lock is held, so it doesn't
+//! allocate, and only uses `try_` locks to look at threads.
+
+use crate::interrupt;
+use crate::memory::{self, PageTable, VirtualAddress, PAGE_SIZE};
+use crate::serial::{self, SerialDevice};
+use crate::sync::{Mutex, Once};
+use crate::thread::{self, State};
+use crate::x86::{self, InterruptFrame, JmpBuf};
+use core::fmt::{self, Write};
+use core::ops::Range;
+
+/// Largest packet in either direction, advertised in qSupported
+const PACKET_SIZE: usize = 0x400;
+
+const MAX_BREAKPOINTS: usize = 32;
+
+const INT3: u8 = 0xCC;
+
+/// The trap flag, which raises #DB after every instruction
+const FLAG_TRAP: usize = 0x100;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// ^C, sent by GDB to stop a running target
+const INTERRUPT_REQUEST: u8 = 0x03;
+
+/// Registers in the order of GDB's amd64 register numbers. The first 17
+/// are 64 bits wide, the rest 32.
+const REGISTER_COUNT: usize = 24;
+const WIDE_REGISTERS: usize = 17;
+
+static DEVICE: Once<&'static SerialDevice> = Once::new();
+static STUB: Mutex<Stub> = Mutex::new(Stub::new());
+
+/// Serve GDB on the serial port called `name`. Returns false if there is
+/// no such port.
+pub fn init(name: &str) -> bool {
+    let device = match serial::device(name) {
+        Some(device) => device,
+        None => return false,
+    };
+    DEVICE.call_once(|| device);
+
+    let registered = interrupt::register(1, "gdb", handle_exception, 0)
+        .and_then(|_| interrupt::register(3, "gdb", handle_exception, 0))
+        // After the port's own handler, which fills its buffer
+        .and_then(|_| {
+            interrupt::register_irq(
+                device.irq(),
+                "gdb",
+                check_interrupt_request,
+                0,
+            )
+        });
+    registered.is_ok()
+}
+
+pub fn enabled() -> bool {
+    DEVICE.get().is_some()
+}
+
+/// The #DB (1) and #BP (3) handler. Reports the stop to GDB and serves
+/// requests until it says to continue.
+fn handle_exception(frame: &mut InterruptFrame, _: usize) {
+    // Breakpoints and single steps in user programs aren't the kernel's
+    if interrupt::from_user_mode(frame) {
+        return;
+    }
+    if frame.interrupt_number == 3 {
+        // int3 leaves ip after itself. If it's one of ours, GDB expects to
+        // see ip at the breakpoint; if it was compiled in, carry on after.
+        let ip = frame.ip.wrapping_sub(1);
+        if STUB.lock().breakpoints.contains(ip) {
+            frame.ip = ip;
+            serve(frame, Stop::Breakpoint);
+            return;
+        }
+    }
+    serve(frame, Stop::Signal(SIGTRAP));
+}
+
+/// Chained after the serial port's interrupt handler. If GDB sent ^C,
+/// stop the kernel where the interrupt arrived.
+fn check_interrupt_request(frame: &mut InterruptFrame, _: usize) {
+    let device = match DEVICE.get() {
+        Some(device) => *device,
+        None => return,
+    };
+    let mut requested = false;
+    while let Some(byte) = device.try_read_byte() {
+        requested |= byte == INTERRUPT_REQUEST;
+    }
+    if requested {
+        x86::without_interrupts(|| serve(frame, Stop::Signal(SIGINT)));
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Stop {
+    Signal(u8),
+    Breakpoint,
+}
+
+fn serve(frame: &mut InterruptFrame, stop: Stop) {
+    let device = match DEVICE.get() {
+        Some(device) => *device,
+        None => return,
+    };
+    // Single-stepping is over once we get here.
+    frame.flags &= !FLAG_TRAP;
+
+    let mut stub = STUB.lock();
+    stub.stopped = Some(gdb_thread_id(thread::try_id().unwrap_or(0)));
+    stub.general = None;
+    stub.reply_stop(stop);
+    stub.send(device);
+
+    loop {
+        stub.receive(device);
+        match stub.handle_packet(frame) {
+            Action::Reply => stub.send(device),
+            Action::Resume => break,
+            Action::ReplyAndResume => {
+                stub.send(device);
+                break;
+            }
+        }
+    }
+    stub.stopped = None;
+}
+
+/// GDB treats thread id 0 as "any thread", so threads are numbered from 1.
+fn gdb_thread_id(id: usize) -> usize {
+    id + 1
+}
+
+fn kernel_thread_id(tid: usize) -> usize {
+    tid - 1
+}
+
+enum Action {
+    Reply,
+    Resume,
+    ReplyAndResume,
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: usize,
+    saved: u8,
+}
+
+struct Breakpoints([Option<Breakpoint>; MAX_BREAKPOINTS]);
+
+impl Breakpoints {
+    fn contains(&self, addr: usize) -> bool {
+        self.0.iter().flatten().any(|bp| bp.addr == addr)
+    }
+
+    fn insert(&mut self, addr: usize) -> bool {
+        if self.contains(addr) {
+            return true;
+        }
+        let slot = match self.0.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let mut saved = [0u8];
+        if !read_memory(addr, &mut saved) || !write_memory(addr, &[INT3]) {
+            return false;
+        }
+        *slot = Some(Breakpoint {
+            addr,
+            saved: saved[0],
+        });
+        true
+    }
+
+    fn remove(&mut self, addr: usize) -> bool {
+        for slot in self.0.iter_mut() {
+            if let Some(bp) = *slot {
+                if bp.addr == addr {
+                    *slot = None;
+                    return write_memory(addr, &[bp.saved]);
+                }
+            }
+        }
+        false
+    }
+
+    fn clear(&mut self) {
+        for slot in self.0.iter_mut() {
+            if let Some(bp) = slot.take() {
+                write_memory(bp.addr, &[bp.saved]);
+            }
+        }
+    }
+}
+
+/// Call `f` with the direct map address of each page-sized piece of
+/// `addr..addr + len` in the current address space, and the offset of the
+/// piece. Going through the direct map lets breakpoints be written into
+/// read-only text. Returns false at the first unmapped page.
+fn for_each_chunk(
+    addr: usize,
+    len: usize,
+    mut f: impl FnMut(usize, *mut u8, usize),
+) -> bool {
+    let table = PageTable::current();
+    let mut done = 0;
+    while done < len {
+        let v = addr.wrapping_add(done);
+        let p = match table.translate(VirtualAddress(v)) {
+            Some((p, _)) if p.0 < memory::direct_map_end() => p,
+            _ => return false,
+        };
+        let chunk = (PAGE_SIZE - v % PAGE_SIZE).min(len - done);
+        f(done, p.direct_map() as *mut u8, chunk);
+        done += chunk;
+    }
+    true
+}
+
+fn read_memory(addr: usize, buf: &mut [u8]) -> bool {
+    for_each_chunk(addr, buf.len(), |offset, src, len| unsafe {
+        let dst = buf[offset..offset + len].as_mut_ptr();
+        core::ptr::copy_nonoverlapping(src, dst, len);
+    })
+}
+
+fn write_memory(addr: usize, buf: &[u8]) -> bool {
+    for_each_chunk(addr, buf.len(), |offset, dst, len| unsafe {
+        let src = buf[offset..offset + len].as_ptr();
+        core::ptr::copy_nonoverlapping(src, dst, len);
+    })
+}
+
+/// The registers of a thread, as far as they are known. The thread that
+/// hit the exception has everything in its interrupt frame, the others
+/// only what `set_jump` saved when they were switched out.
+enum Registers<'a> {
+    Frame(&'a mut InterruptFrame),
+    Saved(&'a mut JmpBuf),
+}
+
+impl Registers<'_> {
+    fn slot(&mut self, n: usize) -> Option<&mut usize> {
+        match self {
+            Registers::Frame(f) => Some(match n {
+                0 => &mut f.ax,
+                1 => &mut f.bx,
+                2 => &mut f.cx,
+                3 => &mut f.dx,
+                4 => &mut f.si,
+                5 => &mut f.di,
+                6 => &mut f.bp,
+                7 => &mut f.sp,
+                8 => &mut f.r8,
+                9 => &mut f.r9,
+                10 => &mut f.r10,
+                11 => &mut f.r11,
+                12 => &mut f.r12,
+                13 => &mut f.r13,
+                14 => &mut f.r14,
+                15 => &mut f.r15,
+                16 => &mut f.ip,
+                17 => &mut f.flags,
+                18 => &mut f.cs,
+                19 => &mut f.ss,
+                20 => &mut f.ds,
+                _ => return None,
+            }),
+            Registers::Saved(buf) => Some(match n {
+                1 => &mut buf.bx,
+                6 => &mut buf.bp,
+                7 => &mut buf.sp,
+                12 => &mut buf.r12,
+                13 => &mut buf.r13,
+                14 => &mut buf.r14,
+                15 => &mut buf.r15,
+                16 => &mut buf.ip,
+                _ => return None,
+            }),
+        }
+    }
+}
+
+fn register_size(n: usize) -> usize {
+    if n < WIDE_REGISTERS {
+        8
+    } else {
+        4
+    }
+}
+
+struct Stub {
+    packet: [u8; PACKET_SIZE],
+    packet_len: usize,
+    reply: [u8; PACKET_SIZE],
+    reply_len: usize,
+    breakpoints: Breakpoints,
+    /// The thread that hit the exception, as a GDB thread id
+    stopped: Option<usize>,
+    /// The thread chosen with `Hg` for register access
+    general: Option<usize>,
+}
+
+impl Stub {
+    const fn new() -> Self {
+        Self {
+            packet: [0; PACKET_SIZE],
+            packet_len: 0,
+            reply: [0; PACKET_SIZE],
+            reply_len: 0,
+            breakpoints: Breakpoints([None; MAX_BREAKPOINTS]),
+            stopped: None,
+            general: None,
+        }
+    }
+
+    /// Wait for a packet with a good checksum, acknowledging it.
+    fn receive(&mut self, device: &SerialDevice) {
+        loop {
+            while device.read_byte_polled() != b'$' {}
+
+            self.packet_len = 0;
+            let mut sum = 0u8;
+            let mut overflow = false;
+            loop {
+                let byte = device.read_byte_polled();
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                if self.packet_len < PACKET_SIZE {
+                    self.packet[self.packet_len] = byte;
+                    self.packet_len += 1;
+                } else {
+                    overflow = true;
+                }
+            }
+            let high = hex_value(device.read_byte_polled());
+            let low = hex_value(device.read_byte_polled());
+            let expected = match (high, low) {
+                (Some(high), Some(low)) => Some(high << 4 | low),
+                _ => None,
+            };
+
+            if !overflow && expected == Some(sum) {
+                device.write_polled(b"+");
+                return;
+            }
+            device.write_polled(b"-");
+        }
+    }
+
+    /// Send the reply until GDB acknowledges it.
+    fn send(&mut self, device: &SerialDevice) {
+        let reply = &self.reply[..self.reply_len];
+        let sum = reply.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        let trailer =
+            [b'#', HEX[(sum >> 4) as usize], HEX[(sum & 0xF) as usize]];
+        loop {
+            device.write_polled(b"$");
+            device.write_polled(reply);
+            device.write_polled(&trailer);
+            match device.read_byte_polled() {
+                b'+' => break,
+                b'-' => continue,
+                // GDB has moved on, probably after a timeout.
+                _ => break,
+            }
+        }
+        self.reply_len = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.reply_len < PACKET_SIZE {
+            self.reply[self.reply_len] = byte;
+            self.reply_len += 1;
+        }
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(HEX[(byte >> 4) as usize]);
+        self.push(HEX[(byte & 0xF) as usize]);
+    }
+
+    fn reply_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn reply_error(&mut self, code: u8) {
+        self.reply_len = 0;
+        self.push(b'E');
+        self.push_hex(code);
+    }
+
+    fn reply_stop(&mut self, stop: Stop) {
+        let tid = self.stopped.unwrap_or(1);
+        let _ = match stop {
+            Stop::Signal(signal) => {
+                write!(self, "T{:02x}thread:{:x};", signal, tid)
+            }
+            Stop::Breakpoint => {
+                write!(self, "T{:02x}thread:{:x};swbreak:;", SIGTRAP, tid)
+            }
+        };
+    }
+
+    /// Append register `n` as little-endian hex, or x's if it isn't known.
+    fn push_register(&mut self, n: usize, value: Option<usize>) {
+        let size = register_size(n);
+        match value {
+            Some(value) => {
+                let bytes = value.to_le_bytes();
+                for &byte in &bytes[..size] {
+                    self.push_hex(byte);
+                }
+            }
+            None => {
+                for _ in 0..size * 2 {
+                    self.push(b'x');
+                }
+            }
+        }
+    }
+
+    /// Run `f` on the registers of the thread chosen with `Hg`. Returns
+    /// None if that thread is gone or locked.
+    fn with_registers<T>(
+        &self,
+        frame: &mut InterruptFrame,
+        f: impl FnOnce(&mut Registers) -> T,
+    ) -> Option<T> {
+        match self.general {
+            Some(tid) if Some(tid) != self.stopped => {
+                let thread = thread::try_get(kernel_thread_id(tid))?;
+                let mut thread = thread.try_write()?;
+                Some(f(&mut Registers::Saved(&mut thread.context)))
+            }
+            _ => Some(f(&mut Registers::Frame(frame))),
+        }
+    }
+
+    fn handle_packet(&mut self, frame: &mut InterruptFrame) -> Action {
+        // Copy the start of the packet out so the handlers can write the
+        // reply. Only G and M packets are longer, and those read
+        // self.packet themselves. Everything here stays small: the stub
+        // runs on whatever thread stack the exception arrived on.
+        let mut packet = [0u8; 64];
+        let len = self.packet_len.min(packet.len());
+        packet[..len].copy_from_slice(&self.packet[..len]);
+        let packet = &packet[..len];
+        self.reply_len = 0;
+
+        match packet.first() {
+            Some(b'?') => {
+                self.reply_stop(Stop::Signal(SIGTRAP));
+            }
+            Some(b'g') => self.read_registers(frame, 0..REGISTER_COUNT),
+            Some(b'G') => self.write_registers(frame),
+            Some(b'p') => match parse_hex(&packet[1..]) {
+                Some(n) if n < REGISTER_COUNT => {
+                    self.read_registers(frame, n..n + 1)
+                }
+                _ => self.reply_error(0x16),
+            },
+            Some(b'P') => self.write_register(frame, &packet[1..]),
+            Some(b'm') => self.read_memory_packet(&packet[1..]),
+            Some(b'M') => self.write_memory_packet(),
+            Some(b'Z') | Some(b'z') => self.breakpoint_packet(packet),
+            Some(b'c') | Some(b's') => {
+                if let Some(ip) = parse_hex(&packet[1..]) {
+                    frame.ip = ip;
+                }
+                if packet[0] == b's' {
+                    frame.flags |= FLAG_TRAP;
+                }
+                return Action::Resume;
+            }
+            Some(b'D') => {
+                self.breakpoints.clear();
+                self.reply_str("OK");
+                return Action::ReplyAndResume;
+            }
+            Some(b'k') => {
+                self.breakpoints.clear();
+                return Action::Resume;
+            }
+            Some(b'H') => {
+                if packet.get(1) == Some(&b'g') {
+                    self.general = parse_thread_id(&packet[2..]);
+                }
+                self.reply_str("OK");
+            }
+            Some(b'T') => match parse_thread_id(&packet[1..]) {
+                Some(tid) if thread_exists(tid) => self.reply_str("OK"),
+                _ => self.reply_error(0x01),
+            },
+            Some(b'q') => self.query(&packet[1..]),
+            _ => {}
+        }
+        Action::Reply
+    }
+
+    fn query(&mut self, query: &[u8]) {
+        if query.starts_with(b"Supported") {
+            let _ = write!(self, "PacketSize={:x};swbreak+", PACKET_SIZE);
+        } else if query == b"fThreadInfo" {
+            self.push(b'm');
+            let listed = thread::try_for_each(|thread| {
+                let _ = write!(self, "{:x},", gdb_thread_id(thread.id()));
+            });
+            if !listed {
+                let _ = write!(self, "{:x},", self.stopped.unwrap_or(1));
+            }
+            // Drop the trailing comma
+            self.reply_len -= 1;
+        } else if query == b"sThreadInfo" {
+            self.push(b'l');
+        } else if query == b"C" {
+            let _ = write!(self, "QC{:x}", self.stopped.unwrap_or(1));
+        } else if query == b"Attached" {
+            self.push(b'1');
+        } else if query.starts_with(b"ThreadExtraInfo,") {
+            let tid = parse_hex(&query[16..]).unwrap_or(0);
+            let description = thread_description(tid);
+            for byte in description.bytes() {
+                self.push_hex(byte);
+            }
+        }
+    }
+
+    fn read_registers(
+        &mut self,
+        frame: &mut InterruptFrame,
+        numbers: Range<usize>,
+    ) {
+        // The registers are collected first, since the thread's lock is
+        // held while looking at them.
+        let mut values = [None; REGISTER_COUNT];
+        let known = self.with_registers(frame, |registers| {
+            for n in numbers.clone() {
+                values[n] = registers.slot(n).map(|value| *value);
+            }
+        });
+        if known.is_none() {
+            return self.reply_error(0x03);
+        }
+        for n in numbers {
+            self.push_register(n, values[n]);
+        }
+    }
+
+    fn write_registers(&mut self, frame: &mut InterruptFrame) {
+        let packet = &self.packet[1..self.packet_len];
+        let written = self.with_registers(frame, |registers| {
+            let mut hex = packet;
+            for n in 0..REGISTER_COUNT {
+                let size = register_size(n);
+                if hex.len() < size * 2 {
+                    break;
+                }
+                let (value, rest) = hex.split_at(size * 2);
+                hex = rest;
+                if let (Some(value), Some(slot)) =
+                    (parse_le_hex(value), registers.slot(n))
+                {
+                    *slot = value;
+                }
+            }
+        });
+        match written {
+            Some(()) => self.reply_str("OK"),
+            None => self.reply_error(0x03),
+        }
+    }
+
+    fn write_register(&mut self, frame: &mut InterruptFrame, args: &[u8]) {
+        let mut parts = args.splitn(2, |&b| b == b'=');
+        let n = parts.next().and_then(parse_hex);
+        let value = parts.next().and_then(parse_le_hex);
+        let (n, value) = match (n, value) {
+            (Some(n), Some(value)) => (n, value),
+            _ => return self.reply_error(0x16),
+        };
+        let written =
+            self.with_registers(frame, |registers| match registers.slot(n) {
+                Some(slot) => {
+                    *slot = value;
+                    true
+                }
+                None => false,
+            });
+        match written {
+            Some(true) => self.reply_str("OK"),
+            _ => self.reply_error(0x03),
+        }
+    }
+
+    fn read_memory_packet(&mut self, args: &[u8]) {
+        let (addr, len) = match parse_addr_len(args) {
+            Some(range) => range,
+            None => return self.reply_error(0x16),
+        };
+        let len = len.min(PACKET_SIZE / 2);
+        let mut buf = [0u8; 64];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(buf.len());
+            if !read_memory(addr.wrapping_add(done), &mut buf[..chunk]) {
+                return self.reply_error(0x0E);
+            }
+            for &byte in &buf[..chunk] {
+                self.push_hex(byte);
+            }
+            done += chunk;
+        }
+    }
+
+    fn write_memory_packet(&mut self) {
+        let packet = &self.packet[1..self.packet_len];
+        let colon = match packet.iter().position(|&b| b == b':') {
+            Some(colon) => colon,
+            None => return self.reply_error(0x16),
+        };
+        let (addr, len) = match parse_addr_len(&packet[..colon]) {
+            Some(range) => range,
+            None => return self.reply_error(0x16),
+        };
+        let hex = &packet[colon + 1..];
+        if hex.len() != len * 2 {
+            return self.reply_error(0x16);
+        }
+        let mut buf = [0u8; 64];
+        let mut ok = true;
+        for (i, chunk) in hex.chunks(buf.len() * 2).enumerate() {
+            let bytes = chunk.len() / 2;
+            for (out, pair) in buf.iter_mut().zip(chunk.chunks(2)) {
+                match parse_hex(pair) {
+                    Some(byte) => *out = byte as u8,
+                    None => ok = false,
+                }
+            }
+            let at = addr.wrapping_add(i * buf.len());
+            ok = ok && write_memory(at, &buf[..bytes]);
+            if !ok {
+                break;
+            }
+        }
+        if ok {
+            self.reply_str("OK");
+        } else {
+            self.reply_error(0x0E);
+        }
+    }
+
+    /// Z0/z0 insert and remove software breakpoints. Other kinds get an
+    /// empty reply, which tells GDB they aren't supported.
+    fn breakpoint_packet(&mut self, packet: &[u8]) {
+        if packet.get(1) != Some(&b'0') {
+            return;
+        }
+        let addr = match packet
+            .get(3..)
+            .and_then(|args| args.split(|&b| b == b',').next())
+            .and_then(parse_hex)
+        {
+            Some(addr) => addr,
+            None => return self.reply_error(0x16),
+        };
+        let done = if packet[0] == b'Z' {
+            self.breakpoints.insert(addr)
+        } else {
+            self.breakpoints.remove(addr)
+        };
+        if done {
+            self.reply_str("OK");
+        } else {
+            self.reply_error(0x0E);
+        }
+    }
+}
+
+impl fmt::Write for Stub {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.reply_str(s);
+        Ok(())
+    }
+}
+
+fn thread_exists(tid: usize) -> bool {
+    tid != 0 && thread::try_get(kernel_thread_id(tid)).is_some()
+}
+
+fn thread_description(tid: usize) -> &'static str {
+    if tid == 0 {
+        return "";
+    }
+    let thread = match thread::try_get(kernel_thread_id(tid)) {
+        Some(thread) => thread,
+        None => return "",
+    };
+    let thread = match thread.try_read() {
+        Some(thread) => thread,
+        None => return "Locked",
+    };
+    match thread.state() {
+        State::Running => "Running",
+        State::Stopped => "Stopped",
+        State::Dead => "Dead",
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(hex: &[u8]) -> Option<usize> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0usize, |value, &byte| {
+        Some(value << 4 | hex_value(byte)? as usize)
+    })
+}
+
+/// Parse a register value, which GDB sends in target byte order.
+fn parse_le_hex(hex: &[u8]) -> Option<usize> {
+    if hex.len() % 2 != 0 || hex.len() > 16 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        bytes[i] = parse_hex(pair)? as u8;
+    }
+    Some(usize::from_le_bytes(bytes))
+}
+
+fn parse_addr_len(args: &[u8]) -> Option<(usize, usize)> {
+    let mut parts = args.splitn(2, |&b| b == b',');
+    let addr = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?)?;
+    Some((addr, len))
+}
+
+/// Parse the thread id in `H` and `T` packets. "0" (any thread) and "-1"
+/// (all threads) both mean the thread that stopped.
+fn parse_thread_id(id: &[u8]) -> Option<usize> {
+    if id == b"-1" {
+        return None;
+    }
+    match parse_hex(id)? {
+        0 => None,
+        tid => Some(tid),
+    }
+}
@@ -1,99 +1,466 @@
-use crate::x86::{self, FaultCode};
-use crate::{serial, thread};
+use crate::memory::VirtualAddress;
+use crate::shell::{self, Command};
+use crate::sync::Mutex;
+use crate::x86::{self, FaultCode, InterruptFrame, SelectorError};
+use crate::{apic, backtrace, process, swap, thread};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 const DETAIL_PRINT: bool = false;
 
-const EXCEPTIONS: [&str; 32] = [
-    "Divide by zero",
-    "Debug",
-    "Non-maskable Interrupt",
-    "Breakpoint",
-    "Overflow Trap",
-    "Bound Range Exceeded",
-    "Invalid Opcode",
-    "Device Not Available",
-    "Double Fault",
-    "Coprocessor Segment Overrun (Deprecated)",
-    "Invalid TSS",
-    "Segment Not Present",
-    "Stack-Segment Fault",
-    "General Protection Fault",
-    "Page Fault",
-    "Reserved",
-    "x87 Floating Point Exception",
-    "Alignment Check",
-    "Machine Check",
-    "SIMD Floating-Point Exception",
-    "Virtualization Exception",
-    "Reserved",
-    "Reserved",
-    "Reserved",
-    "Reserved",
-    "Reserved",
-    "Reserved",
-    "Reserved",
-    "Reserved",
-    "Reserved",
-    "Security Exception",
-    "Reserved",
-];
-//
-// enum Exception {
-//     DivByZero = 0,
-//     Debug = 1,
-//     Nmi = 2,
-//     Breakpoint = 3,
-//     OverflowTrap = 4,
-//     OutOfBounds = 5,
-//     InvalidOpcode = 6,
-//     NoDevice = 7,
-//     DoubleFault = 8,
-//     InvalidTSS = 10,
-//     InvalidSegment = 11,
-//     StackFault = 12,
-//     GeneralProtectionFault = 13,
-//     PageFault = 14,
-//     X87 = 16,
-//     AlignmentCheck = 17,
-//     MachineCheck = 18,
-//     Simd = 19,
-//     Virtualization = 20,
-//     Security = 30,
-//     Reserved,
-// }
-//
-// impl Display for Exception {
-//     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-//         let name = match *self {
-//             DivByZero => "Divide by zero",
-//             Debug => "Debug",
-//             Nmi => "Non-maskable Interrupt",
-//             Breakpoint => "Breakpoint",
-//             OverflowTrap => "Overflow Trap",
-//             OutOfBounds => "Bound Range Exceeded",
-//             InvalidOpcode => "Invalid Opcode",
-//             NoDevice => "Device Not Available",
-//             DoubleFault => "Double Fault",
-//             InvalidTSS => "Invalid TSS",
-//             InvalidSegment => "Segment Not Present",
-//             StackFault => "Stack-Segment Fault",
-//             GeneralProtectionFault => "General Protection Fault",
-//             PageFault => "Page Fault",
-//             X87 => "x87 Floating Point Exception",
-//             AlignmentCheck => "Alignment Check",
-//             MachineCheck => "Machine Check",
-//             Simd => "SIMD Floating-Point Exception",
-//             Virtualization => "Virtualization Exception",
-//             Security => "Security Exception",
-//             _ => "Reserved",
-//         }
-//         write!(f, "{}", name)
-//     }
-// }
+// Signal numbers, as on Linux. A process killed by an exception exits with
+// 128 plus the signal, like a shell reports a process killed by a signal.
+const SIGILL: i32 = 4;
+const SIGTRAP: i32 = 5;
+const SIGBUS: i32 = 7;
+const SIGFPE: i32 = 8;
+const SIGSEGV: i32 = 11;
+
+const VECTORS: usize = 256;
+
+/// IRQ n is delivered on vector IRQ_BASE + n, by the PIC or the APIC
+pub const IRQ_BASE: usize = 32;
+pub const IRQ_COUNT: usize = 16;
+
+/// How many handlers can share one vector
+const MAX_SHARED: usize = 4;
+
+/// An interrupt handler, called with the interrupted state and the data
+/// it was registered with.
+pub type Handler = fn(frame: &mut InterruptFrame, data: usize);
+
+#[derive(Clone, Copy)]
+struct Registration {
+    id: usize,
+    name: &'static str,
+    handler: Handler,
+    data: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: usize,
+    id: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    BadVector,
+    /// MAX_SHARED handlers are already registered on the vector
+    Full,
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            RegisterError::BadVector => "no such vector",
+            RegisterError::Full => "too many handlers on one vector",
+        };
+        f.write_str(s)
+    }
+}
+
+type Slots = [Option<Registration>; MAX_SHARED];
+
+/// Handlers for each vector, called in the order they were registered.
+/// Only locked with interrupts disabled.
+static HANDLERS: Mutex<[Slots; VECTORS]> =
+    Mutex::new([[None; MAX_SHARED]; VECTORS]);
+
+static NEXT_HANDLER_ID: AtomicUsize = AtomicUsize::new(1);
+
+fn irq_for_vector(vector: usize) -> Option<usize> {
+    if (IRQ_BASE..IRQ_BASE + IRQ_COUNT).contains(&vector) {
+        Some(vector - IRQ_BASE)
+    } else {
+        None
+    }
+}
+
+fn unmask_irq(irq: usize) {
+    if apic::enabled() {
+        apic::unmask_irq(irq);
+    } else {
+        x86::unmask_irq(irq);
+    }
+}
+
+fn mask_irq(irq: usize) {
+    if apic::enabled() {
+        apic::mask_irq(irq);
+    } else {
+        x86::mask_irq(irq);
+    }
+}
+
+fn send_eoi(irq: usize) {
+    if apic::enabled() {
+        apic::eoi();
+    } else {
+        x86::send_eoi(irq);
+    }
+}
+
+/// Install `handler` on `vector`, after any handlers already there. It
+/// gets `data` every time it's called.
+pub fn register(
+    vector: usize,
+    name: &'static str,
+    handler: Handler,
+    data: usize,
+) -> Result<HandlerId, RegisterError> {
+    if vector >= VECTORS {
+        return Err(RegisterError::BadVector);
+    }
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    x86::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slot = handlers[vector]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegisterError::Full)?;
+        *slot = Some(Registration {
+            id,
+            name,
+            handler,
+            data,
+        });
+        Ok(HandlerId { vector, id })
+    })
+}
+
+/// Install `handler` on an IRQ line and unmask it. The line is
+/// acknowledged before handlers run, so they don't need to send EOI.
+pub fn register_irq(
+    irq: usize,
+    name: &'static str,
+    handler: Handler,
+    data: usize,
+) -> Result<HandlerId, RegisterError> {
+    if irq >= IRQ_COUNT {
+        return Err(RegisterError::BadVector);
+    }
+    let id = register(IRQ_BASE + irq, name, handler, data)?;
+    unmask_irq(irq);
+    Ok(id)
+}
+
+/// Remove a handler. An IRQ line is masked again once it has no handlers.
+pub fn unregister(id: HandlerId) {
+    let now_empty = x86::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slots = &mut handlers[id.vector];
+        let index = slots.iter().position(|slot| match slot {
+            Some(registration) => registration.id == id.id,
+            None => false,
+        });
+        if let Some(index) = index {
+            // Keep the rest in registration order
+            for i in index..MAX_SHARED - 1 {
+                slots[i] = slots[i + 1];
+            }
+            slots[MAX_SHARED - 1] = None;
+        }
+        slots.iter().all(Option::is_none)
+    });
+    if let (true, Some(irq)) = (now_empty, irq_for_vector(id.vector)) {
+        mask_irq(irq);
+    }
+}
+
+/// Run the handlers on `vector`. Returns false if there are none.
+fn dispatch(vector: usize, frame: &mut InterruptFrame) -> bool {
+    // Handlers are copied out so the lock isn't held while they run: the
+    // timer handler switches threads and may not come back for a while.
+    let slots = x86::without_interrupts(|| HANDLERS.lock()[vector]);
+    let mut handled = false;
+    for registration in slots.iter().flatten() {
+        (registration.handler)(frame, registration.data);
+        handled = true;
+    }
+    handled
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+/// How many times each vector has been taken
+static COUNTS: [AtomicUsize; VECTORS] = [ZERO; VECTORS];
+
+pub fn count(vector: usize) -> usize {
+    COUNTS[vector].load(Ordering::Relaxed)
+}
+
+pub fn register_commands() {
+    shell::register(Command {
+        name: "irqs",
+        usage: "irqs",
+        help: "show how many times each interrupt has been taken",
+        run: |_| {
+            for vector in 0..VECTORS {
+                let count = count(vector);
+                if count == 0 {
+                    continue;
+                }
+                print!("{:>4} {:>12}", vector, count);
+                if let Some(exception) = Exception::from_vector(vector) {
+                    print!(" {}", exception);
+                }
+                let slots = x86::without_interrupts(|| HANDLERS.lock()[vector]);
+                for registration in slots.iter().flatten() {
+                    print!(" {}", registration.name);
+                }
+                println!();
+            }
+            Ok(())
+        },
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivByZero,
+    Debug,
+    Nmi,
+    Breakpoint,
+    OverflowTrap,
+    OutOfBounds,
+    InvalidOpcode,
+    NoDevice,
+    DoubleFault,
+    CoprocessorSegmentOverrun,
+    InvalidTss,
+    InvalidSegment,
+    StackFault,
+    GeneralProtectionFault,
+    PageFault,
+    X87,
+    AlignmentCheck,
+    MachineCheck,
+    Simd,
+    Virtualization,
+    ControlProtection,
+    Security,
+    Reserved(usize),
+}
+
+impl Exception {
+    pub const COUNT: usize = 32;
+
+    /// The signal that kills a process raising this exception in user
+    /// mode. None for the exceptions that are the kernel's problem.
+    fn user_signal(self) -> Option<i32> {
+        use Exception::*;
+        match self {
+            DivByZero | X87 | Simd => Some(SIGFPE),
+            Debug | Breakpoint => Some(SIGTRAP),
+            InvalidOpcode | NoDevice => Some(SIGILL),
+            AlignmentCheck => Some(SIGBUS),
+            OverflowTrap
+            | OutOfBounds
+            | InvalidSegment
+            | StackFault
+            | GeneralProtectionFault
+            | PageFault
+            | ControlProtection => Some(SIGSEGV),
+            _ => None,
+        }
+    }
+
+    /// The exception raised on `vector`, or None if it isn't one of the
+    /// 32 exception vectors.
+    pub fn from_vector(vector: usize) -> Option<Self> {
+        use Exception::*;
+        let exception = match vector {
+            0 => DivByZero,
+            1 => Debug,
+            2 => Nmi,
+            3 => Breakpoint,
+            4 => OverflowTrap,
+            5 => OutOfBounds,
+            6 => InvalidOpcode,
+            7 => NoDevice,
+            8 => DoubleFault,
+            9 => CoprocessorSegmentOverrun,
+            10 => InvalidTss,
+            11 => InvalidSegment,
+            12 => StackFault,
+            13 => GeneralProtectionFault,
+            14 => PageFault,
+            16 => X87,
+            17 => AlignmentCheck,
+            18 => MachineCheck,
+            19 => Simd,
+            20 => Virtualization,
+            21 => ControlProtection,
+            30 => Security,
+            v if v < Self::COUNT => Reserved(v),
+            _ => return None,
+        };
+        Some(exception)
+    }
+
+    /// Whether the error code is a segment selector, decoded by
+    /// SelectorError.
+    pub fn has_selector_error(self) -> bool {
+        use Exception::*;
+        matches!(
+            self,
+            InvalidTss | InvalidSegment | StackFault | GeneralProtectionFault
+        )
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Exception::*;
+        let name = match *self {
+            DivByZero => "Divide by zero",
+            Debug => "Debug",
+            Nmi => "Non-maskable Interrupt",
+            Breakpoint => "Breakpoint",
+            OverflowTrap => "Overflow Trap",
+            OutOfBounds => "Bound Range Exceeded",
+            InvalidOpcode => "Invalid Opcode",
+            NoDevice => "Device Not Available",
+            DoubleFault => "Double Fault",
+            CoprocessorSegmentOverrun => {
+                "Coprocessor Segment Overrun (Deprecated)"
+            }
+            InvalidTss => "Invalid TSS",
+            InvalidSegment => "Segment Not Present",
+            StackFault => "Stack-Segment Fault",
+            GeneralProtectionFault => "General Protection Fault",
+            PageFault => "Page Fault",
+            X87 => "x87 Floating Point Exception",
+            AlignmentCheck => "Alignment Check",
+            MachineCheck => "Machine Check",
+            Simd => "SIMD Floating-Point Exception",
+            Virtualization => "Virtualization Exception",
+            ControlProtection => "Control Protection Exception",
+            Security => "Security Exception",
+            Reserved(_) => "Reserved",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Print everything known about an interrupt nobody handled. This uses
+/// dprintln and no locks, since the system is about to panic.
+fn crash_report(frame: &InterruptFrame) {
+    let vector = frame.interrupt_number;
+    match Exception::from_vector(vector) {
+        Some(exception) => {
+            dprintln!("{} (vector {}) at {:#x}", exception, vector, frame.ip);
+            if exception.has_selector_error() {
+                let error = SelectorError::from_code(frame.error_code);
+                dprintln!("error code: {:#x} ({})", frame.error_code, error);
+            } else {
+                dprintln!("error code: {:#x}", frame.error_code);
+            }
+        }
+        None => {
+            dprintln!("Unexpected interrupt {} at {:#x}", vector, frame.ip);
+        }
+    }
+
+    // A stack overflow page faults on the guard page, then double faults
+    // because the page fault can't be pushed on the stack
+    if Exception::from_vector(vector) == Some(Exception::DoubleFault) {
+        if let Some(id) = thread::try_stack_overflowed(x86::read_cr2()) {
+            dprintln!("kernel stack overflow in thread {}", id);
+        }
+    }
+
+    match thread::try_id() {
+        Some(id) => {
+            dprintln!("in thread {}", id);
+        }
+        None => {
+            dprintln!("in an unknown thread (thread set locked)");
+        }
+    }
+
+    let f = frame;
+    dprintln!(
+        "rax {:016x} rbx {:016x} rcx {:016x} rdx {:016x}",
+        f.ax,
+        f.bx,
+        f.cx,
+        f.dx
+    );
+    dprintln!(
+        "rsi {:016x} rdi {:016x} rbp {:016x} rsp {:016x}",
+        f.si,
+        f.di,
+        f.bp,
+        f.sp
+    );
+    dprintln!(
+        "r8  {:016x} r9  {:016x} r10 {:016x} r11 {:016x}",
+        f.r8,
+        f.r9,
+        f.r10,
+        f.r11
+    );
+    dprintln!(
+        "r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}",
+        f.r12,
+        f.r13,
+        f.r14,
+        f.r15
+    );
+    dprintln!(
+        "rip {:016x} rflags {:08x} cs {:04x} ss {:04x} ds {:04x}",
+        f.ip,
+        f.flags,
+        f.cs,
+        f.ss,
+        f.ds
+    );
+    dprintln!(
+        "cr0 {:016x} cr2 {:016x} cr3 {:016x} cr4 {:016x}",
+        x86::read_cr0(),
+        x86::read_cr2(),
+        x86::read_cr3(),
+        x86::read_cr4()
+    );
+    backtrace::print_interrupted(frame.ip, frame.bp);
+}
+
+/// Kill the process whose thread raised `exception` in user mode, instead
+/// of the kernel. The thread exits on the way out of c_interrupt_shim,
+/// unless it came in on an interrupt stack; then it gets as far as the
+/// next interrupt or syscall. Single-stepping is turned off so a #DB
+/// doesn't follow every instruction until then.
+fn kill_user_process(
+    frame: &mut InterruptFrame,
+    exception: Exception,
+    signal: i32,
+) {
+    let process = match process::current() {
+        Some(process) => process,
+        None => return,
+    };
+    warn!(
+        "process {} (thread {}) killed by {} at {:#x}",
+        process.read().id(),
+        thread::id(),
+        exception,
+        frame.ip
+    );
+    process::kill(&process, signal);
+    frame.flags &= !x86::FLAG_TRAP;
+}
+
+pub fn from_user_mode(frame: &InterruptFrame) -> bool {
+    frame.cs & 3 == 3
+}
 
 #[no_mangle]
 pub unsafe extern "C" fn c_interrupt_shim(frame: *mut x86::InterruptFrame) {
     let interrupt = (*frame).interrupt_number;
+    COUNTS[interrupt].fetch_add(1, Ordering::Relaxed);
 
     if DETAIL_PRINT {
         println!("interrupt: {}", interrupt);
@@ -101,38 +468,72 @@ pub unsafe extern "C" fn c_interrupt_shim(frame: *mut x86::InterruptFrame) {
         dprintln!("{:#x?}", f);
     }
 
-    #[allow(clippy::match_overlapping_arm)]
+    let frame = &mut *frame;
+    handle_interrupt(interrupt, frame);
+
+    // A killed process's threads end here rather than go back to it, but
+    // an interrupt stack has to be returned from.
+    if from_user_mode(frame) && !x86::uses_ist(interrupt) {
+        process::exit_if_killed();
+    }
+}
+
+fn handle_interrupt(interrupt: usize, frame: &mut InterruptFrame) {
+    if interrupt == apic::SPURIOUS_VECTOR {
+        return;
+    }
+    if let Some(irq) = irq_for_vector(interrupt) {
+        send_eoi(irq);
+    }
+    // Exceptions from user mode are the process's, not something for the
+    // kernel's hooks like the debugger stub
+    let user_exception =
+        from_user_mode(frame) && Exception::from_vector(interrupt).is_some();
+    if !user_exception && dispatch(interrupt, frame) {
+        return;
+    }
+
     match interrupt {
         14 => {
+            if swap::handle_fault(VirtualAddress(x86::read_cr2())) {
+                return;
+            }
+
+            if let Some(fixup) = x86::exception_fixup(frame.ip) {
+                frame.ip = fixup;
+                return;
+            }
+
+            if from_user_mode(frame) {
+                kill_user_process(frame, Exception::PageFault, SIGSEGV);
+                return;
+            }
+
             dprintln!("Page fault at {:#x}", x86::read_cr2());
-            dprintln!("Fault occurred at ({:#x}) <.>", (*frame).ip);
+            dprintln!("Fault occurred at ({:#x}) <.>", frame.ip);
 
-            if let Some(fault) =
-                FaultCode::from_bits((*frame).error_code as u16)
-            {
+            if let Some(fault) = FaultCode::from_bits(frame.error_code as u16) {
                 dprintln!("Page fault code: {}", fault);
             }
-            panic!();
-        }
-        32 => {
-            x86::send_eoi(interrupt - 32);
-            thread::schedule();
-        }
-        36 => {
-            serial::GLOBAL_SERIAL.lock().handle_irq();
-            x86::send_eoi(interrupt - 32);
-        }
-        32..=48 => {
-            x86::send_eoi(interrupt - 32);
+            crash_report(frame);
+            panic!("unhandled page fault");
         }
+        // An IRQ nobody has hooked; it was acknowledged above
+        _ if irq_for_vector(interrupt).is_some() => {}
         _ => {
-            dprintln!(
-                "Interrupt {} ({}) Triggered at {:x}",
-                interrupt,
-                EXCEPTIONS[interrupt],
-                (*frame).ip
-            );
-            panic!();
+            if let Some(exception) = Exception::from_vector(interrupt) {
+                if let (true, Some(signal)) =
+                    (from_user_mode(frame), exception.user_signal())
+                {
+                    kill_user_process(frame, exception, signal);
+                    return;
+                }
+            }
+            crash_report(frame);
+            match Exception::from_vector(interrupt) {
+                Some(exception) => panic!("unhandled exception: {}", exception),
+                None => panic!("unexpected interrupt {}", interrupt),
+            }
         }
     }
 }
@@ -24,18 +24,42 @@ mod debug;
 #[macro_use]
 mod serial;
 
+#[macro_use]
+mod log;
+
+mod acpi;
 #[cfg(target_os = "none")]
 mod allocator;
+mod apic;
+mod backtrace;
+mod block;
+mod elf;
+mod gdb;
 mod interrupt;
 mod memory;
+mod pcid;
 mod phy_map;
+mod process;
+mod shell;
+mod shm;
+mod swap;
+mod syscall;
 mod thread;
+mod time;
+mod timer;
+mod tty;
+mod user;
 mod util;
+mod vga;
 mod x86;
 
-use memory::LOAD_OFFSET;
+use alloc::boxed::Box;
+use memory::{PhysicalAddress, PhysicalRange, LOAD_OFFSET, PAGE_SIZE};
 
 const USE_TIMER: bool = true;
+const USE_RAMDISK_SWAP: bool = false;
+const USE_GDB_STUB: bool = false;
+const RAMDISK_SWAP_SIZE: usize = 4 * 1024 * 1024;
 const MULTIBOOT2_MAGIC: u32 = 0x36d76289;
 
 #[no_mangle]
@@ -51,44 +75,101 @@ pub extern "C" fn kernel_main(
         unsafe { multiboot2::load_with_offset(multiboot_info, LOAD_OFFSET) };
 
     if let Some(boot_loader_name_tag) = boot_info.boot_loader_name_tag() {
-        println!("bootloader is: {}", boot_loader_name_tag.name());
+        info!("bootloader is: {}", boot_loader_name_tag.name());
     }
 
+    memory::paging_init();
+
     if let Some(memory_map_tag) = boot_info.memory_map_tag() {
         phy_map::map_init(memory_map_tag.all_memory_areas());
     }
+    // Modules sit in available memory, so they have to be claimed before
+    // anything else is allocated.
+    for module_tag in boot_info.module_tags() {
+        phy_map::leak(module_range(module_tag).align_out(PAGE_SIZE));
+    }
+    if let Some(memory_map_tag) = boot_info.memory_map_tag() {
+        memory::direct_map_init(memory_map_tag.all_memory_areas());
+    }
+
+    pcid::init();
+
+    if USE_RAMDISK_SWAP {
+        swap::init(Box::new(block::RamDisk::new(RAMDISK_SWAP_SIZE)));
+    }
 
     for module_tag in boot_info.module_tags() {
-        println!("module: {}", module_tag.name());
+        info!("module: {}", module_tag.name());
+        let bytes = module_bytes(&module_tag);
+        if module_tag.name() == "symbols" {
+            backtrace::load_symbols(bytes);
+        } else if elf::is_elf(bytes) {
+            elf::add_program(module_tag.name(), bytes);
+        }
     }
 
     x86::idt_init();
     x86::pic_init();
-    x86::unmask_irq(4);
-    x86::timer_init(1000);
-    x86::unmask_irq(0);
-
-    fn prloop() {
-        let id = thread::id();
-        for _ in 0..10 {
-            dprint!("[{}]", id);
-            thread::schedule();
-        }
+    if apic::init() {
+        info!("using the APIC for interrupts");
+    } else {
+        info!("using the PIC for interrupts");
     }
+    syscall::init();
+    serial::register_irqs();
+    serial::use_interrupt_tx();
 
-    for _ in 0..150 {
-        thread::spawn(prloop);
+    for device in serial::devices() {
+        info!("{} (irq {})", device.name(), device.irq());
+    }
+    if serial::route_machine_channel("com2") {
+        info!("machine-readable output on com2");
+    }
+    if USE_GDB_STUB && gdb::init("com3") {
+        info!("gdb: waiting for a debugger on com3");
+        x86::break_point();
     }
+    x86::timer_init(1000);
+    time::init();
+    timer::init();
+    thread::start_preemption();
+
+    elf::register_commands();
+    interrupt::register_commands();
+    log::register_commands();
+    memory::register_commands();
+    phy_map::register_commands();
+    process::register_commands();
+    thread::register_commands();
+    time::register_commands();
+    timer::register_commands();
+    shell::init();
 
     x86::enable_irqs();
     thread::schedule();
     panic!("thread::schedule should never return to main");
 }
 
+fn module_range(module_tag: &multiboot2::ModuleTag) -> PhysicalRange {
+    PhysicalRange::new(
+        module_tag.start_address() as usize,
+        module_tag.end_address() as usize,
+    )
+}
+
+/// The contents of a module, through the direct map
+fn module_bytes(module_tag: &multiboot2::ModuleTag) -> &'static [u8] {
+    let range = module_range(module_tag);
+    let start = PhysicalAddress(range.start).direct_map() as *const u8;
+    unsafe { core::slice::from_raw_parts(start, range.len()) }
+}
+
 #[cfg(target_os = "none")]
 #[panic_handler]
 fn panic(panic_info: &PanicInfo) -> ! {
+    serial::emergency_flush();
     dprintln!("{}", panic_info);
+    backtrace::print();
     loop {
         x86::disable_interrupts();
         x86::pause();
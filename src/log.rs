@@ -0,0 +1,367 @@
+//! The kernel log. Records have a level, the module they came from, a
+//! timestamp and the thread that logged them. Every record that passes
+//! the level filters is kept in a ring buffer, which `dmesg` reads back,
+//! and handed to each sink.
+
+use crate::shell::{self, Command};
+use crate::sync::Mutex;
+use crate::{serial, thread, time, vga, x86};
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Level> {
+        Self::ALL.iter().copied().find(|level| level.name() == name)
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+pub struct Record<'a> {
+    pub level: Level,
+    /// The module path, without the crate name
+    pub module: &'a str,
+    /// Milliseconds since the clock started
+    pub time: usize,
+    pub thread: usize,
+    pub args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>5}.{:03}] {:<5} {:>3} {}: {}",
+            self.time / 1000,
+            self.time % 1000,
+            self.level,
+            self.thread,
+            self.module,
+            self.args
+        )
+    }
+}
+
+/// Somewhere log records go.
+pub trait Sink: Sync {
+    fn write(&self, record: &Record);
+}
+
+/// Human-readable records on the serial console
+pub struct ConsoleSink;
+
+impl Sink for ConsoleSink {
+    fn write(&self, record: &Record) {
+        serial::console().write_fmt(format_args!("{}\n", record));
+    }
+}
+
+/// Human-readable records on the VGA text screen
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn write(&self, record: &Record) {
+        vga::print(format_args!("{}\n", record));
+    }
+}
+
+/// Tab-separated records on the machine-readable serial channel, if one
+/// is routed
+pub struct MachineSink;
+
+impl Sink for MachineSink {
+    fn write(&self, record: &Record) {
+        serial::machine_print(format_args!(
+            "log\t{}\t{}\t{}\t{}\t{}\n",
+            record.time,
+            record.level.name(),
+            record.thread,
+            record.module,
+            record.args
+        ));
+    }
+}
+
+static CONSOLE_SINK: ConsoleSink = ConsoleSink;
+static VGA_SINK: VgaSink = VgaSink;
+static MACHINE_SINK: MachineSink = MachineSink;
+
+lazy_static! {
+    static ref SINKS: Mutex<Vec<&'static dyn Sink>> =
+        Mutex::new(vec![&CONSOLE_SINK, &VGA_SINK, &MACHINE_SINK]);
+}
+
+pub fn add_sink(sink: &'static dyn Sink) {
+    x86::without_interrupts(|| SINKS.lock().push(sink));
+}
+
+const DEFAULT_LEVEL: Level = Level::Info;
+
+/// The most verbose level any filter allows, so most disabled records are
+/// thrown away without looking at the filters
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL as usize);
+
+struct Filters {
+    default: Level,
+    /// Module path prefixes and their levels. The longest match wins.
+    modules: Vec<(String, Level)>,
+}
+
+impl Filters {
+    fn level_for(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .filter(|(prefix, _)| module_matches(module, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    fn max_level(&self) -> Level {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Level::max)
+    }
+}
+
+fn module_matches(module: &str, prefix: &str) -> bool {
+    module == prefix
+        || (module.starts_with(prefix)
+            && module[prefix.len()..].starts_with("::"))
+}
+
+static FILTERS: Mutex<Filters> = Mutex::new(Filters {
+    default: DEFAULT_LEVEL,
+    modules: Vec::new(),
+});
+
+/// Set the level for `module` and the modules inside it, or the default
+/// level for every module without its own if `module` is None.
+pub fn set_level(module: Option<&str>, level: Level) {
+    x86::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        match module {
+            None => filters.default = level,
+            Some(module) => {
+                filters.modules.retain(|(prefix, _)| prefix != module);
+                filters.modules.push((module.to_string(), level));
+            }
+        }
+        MAX_LEVEL.store(filters.max_level() as usize, Ordering::Relaxed);
+    });
+}
+
+/// Drop the crate name from a module path, "cardinal::memory" -> "memory"
+fn short_module(path: &str) -> &str {
+    match path.find("::") {
+        Some(i) => &path[i + 2..],
+        None => path,
+    }
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    if level as usize > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    let module = short_module(module);
+    x86::without_interrupts(|| level <= FILTERS.lock().level_for(module))
+}
+
+const RING_SIZE: usize = 64 * 1024;
+
+/// The last RING_SIZE bytes of formatted records
+struct Ring {
+    buffer: [u8; RING_SIZE],
+    /// Where the next byte goes
+    head: usize,
+    /// Whether the buffer has filled up and old records were overwritten
+    wrapped: bool,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buffer: [0; RING_SIZE],
+            head: 0,
+            wrapped: false,
+        }
+    }
+
+    /// The contents, oldest first, starting at a record boundary. They
+    /// wrap around the end of the buffer, so come in two parts.
+    fn contents(&self) -> (&[u8], &[u8]) {
+        if !self.wrapped {
+            return (&self.buffer[..self.head], &[]);
+        }
+        let older = &self.buffer[self.head..];
+        // The oldest record was partly overwritten, so skip the rest of it
+        let start = older
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|i| i + 1)
+            .unwrap_or(older.len());
+        (&older[start..], &self.buffer[..self.head])
+    }
+}
+
+impl fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buffer[self.head] = byte;
+            self.head += 1;
+            if self.head == RING_SIZE {
+                self.head = 0;
+                self.wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// Milliseconds since the clock was started
+fn timestamp() -> usize {
+    time::uptime().as_millis() as usize
+}
+
+/// Log a record from `module`. Use the macros instead of calling this.
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+    let record = Record {
+        level,
+        module: short_module(module),
+        time: timestamp(),
+        thread: thread::try_id().unwrap_or(0),
+        args,
+    };
+
+    x86::without_interrupts(|| {
+        let _ = writeln!(RING.lock(), "{}", record);
+        for sink in SINKS.lock().iter() {
+            sink.write(&record);
+        }
+    });
+}
+
+/// Print everything still in the ring buffer to the console, oldest
+/// first. It's written straight from the buffer, as a copy of it would
+/// never be given back by the heap.
+pub fn dmesg() {
+    x86::without_interrupts(|| {
+        let ring = RING.lock();
+        let (older, newer) = ring.contents();
+        serial::console().write_bytes(older);
+        serial::console().write_bytes(newer);
+    });
+}
+
+pub fn register_commands() {
+    shell::register(Command {
+        name: "dmesg",
+        usage: "dmesg",
+        help: "print the kernel log",
+        run: |_| {
+            dmesg();
+            Ok(())
+        },
+    });
+    shell::register(Command {
+        name: "log",
+        usage: "log [module] [level]",
+        help: "show or set log levels, e.g. log memory trace",
+        run: |args| {
+            match args {
+                [] => {
+                    let filters = x86::without_interrupts(|| {
+                        let filters = FILTERS.lock();
+                        (filters.default, filters.modules.clone())
+                    });
+                    println!("default: {}", filters.0);
+                    for (module, level) in filters.1 {
+                        println!("{}: {}", module, level);
+                    }
+                }
+                [level] => {
+                    let level = Level::from_name(level).ok_or("bad level")?;
+                    set_level(None, level);
+                }
+                [module, level] => {
+                    let level = Level::from_name(level).ok_or("bad level")?;
+                    set_level(Some(module), level);
+                }
+                _ => return Err("too many arguments"),
+            }
+            Ok(())
+        },
+    });
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::log($level, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
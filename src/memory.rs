@@ -1,8 +1,14 @@
-use crate::phy_map;
-use crate::util::round_down;
+use crate::shell::{self, Command};
+use crate::shm::{SharedMapping, SharedMemory};
+use crate::util::{round_down, round_up};
+use crate::x86;
+use crate::{pcid, phy_map, swap};
+use alloc::vec::Vec;
 use core::fmt;
 use core::mem::size_of;
 use core::ops::{Add, BitAnd, BitOr, Range};
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct PhysicalAddress(pub usize);
@@ -16,6 +22,12 @@ pub struct PhysicalRange {
     pub end: usize,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct VirtualRange {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug)]
 pub struct PageTable(pub PhysicalPage);
 
@@ -26,34 +38,96 @@ pub struct PageTableEntry(usize);
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct VirtualAddress(pub usize);
 
-#[derive(Copy, Clone, Debug)]
-enum PagingError<'a> {
-    Other(&'a str),
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PagingError {
+    /// Something is already mapped at the address
+    AlreadyMapped,
+    /// Nothing is mapped at the address
+    NotMapped,
+    /// A huge page covers the address where a page table is needed
+    HugePageConflict,
+    /// There was no memory for a new page table
+    OutOfMemory,
+    /// An address is not aligned to the page size being mapped
+    Unaligned,
+}
+
+impl fmt::Display for PagingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            PagingError::AlreadyMapped => "already mapped",
+            PagingError::NotMapped => "not mapped",
+            PagingError::HugePageConflict => "conflicts with a huge page",
+            PagingError::OutOfMemory => "out of memory for page tables",
+            PagingError::Unaligned => "unaligned address",
+        };
+        write!(f, "{}", description)
+    }
 }
 
 pub const LOAD_OFFSET: usize = 0xFFFF_FFFF_8000_0000;
 pub const PHY_OFFSET: usize = 0xFFFF_8000_0000_0000;
 pub const PAGE_SIZE: usize = 0x1000;
+pub const HUGE_PAGE_SIZE: usize = 0x20_0000;
+pub const GIANT_PAGE_SIZE: usize = 0x4000_0000;
+
+/// boot.asm maps the first 1GiB at PHY_OFFSET with a single huge page
+/// (PHYS_PDPT), direct_map_init extends that to cover all of RAM.
+const BOOT_DIRECT_MAP_END: usize = GIANT_PAGE_SIZE;
+
+static DIRECT_MAP_END: AtomicUsize = AtomicUsize::new(BOOT_DIRECT_MAP_END);
+
+/// 4 or 5, depending on whether boot.asm turned on LA57
+static PAGING_LEVELS: AtomicUsize = AtomicUsize::new(4);
+
+/// The boot page table, which maps only the kernel
+static KERNEL_TABLE: AtomicUsize = AtomicUsize::new(0);
 
 pub const PAGE_MASK: usize = 0xFFFF_FFFF_FFFF_F000;
 pub const PAGE_OFFSET_MASK: usize = !PAGE_MASK;
-pub const PAGE_ADDR_MASK: usize = 0x00FF_FFFF_FFFF_F000;
-pub const PAGE_FLAGS_MASK: usize = 0xFF00_0000_0000_0FFF;
-
-pub const PAGE_PRESENT: usize = 0x01;
-pub const PAGE_WRITEABLE: usize = 0x02;
-pub const PAGE_USERMODE: usize = 0x04;
-pub const PAGE_ACCESSED: usize = 0x20;
-pub const PAGE_DIRTY: usize = 0x40;
-pub const PAGE_ISHUGE: usize = 0x80;
-pub const PAGE_GLOBAL: usize = 0x100;
-pub const PAGE_OS_RESERVED1: usize = 0x200;
-pub const PAGE_OS_RESERVED2: usize = 0x400;
-pub const PAGE_OS_RESERVED3: usize = 0x800;
-
-pub const PAGE_TABLE_FLAGS: usize = PAGE_PRESENT | PAGE_WRITEABLE;
-pub const PAGE_COPYONWRITE: usize = PAGE_OS_RESERVED1;
-pub const PAGE_UNBACKED: usize = 0x1000000;
+pub const PAGE_ADDR_MASK: usize = 0x000F_FFFF_FFFF_F000;
+pub const PAGE_FLAGS_MASK: usize = 0xFFF0_0000_0000_0FFF;
+
+bitflags! {
+    pub struct PageFlags: usize {
+        const PRESENT = 1 << 0;
+        const WRITEABLE = 1 << 1;
+        const USERMODE = 1 << 2;
+        const WRITE_THROUGH = 1 << 3;
+        const CACHE_DISABLE = 1 << 4;
+        const ACCESSED = 1 << 5;
+        const DIRTY = 1 << 6;
+        /// In a PDPT or PD entry, maps a 1GiB or 2MiB page
+        const ISHUGE = 1 << 7;
+        /// In a PT entry, the high bit of the PAT index
+        const PAT = 1 << 7;
+        const GLOBAL = 1 << 8;
+
+        // Bits 9-11 are ignored by the hardware and free for the OS
+        const COPYONWRITE = 1 << 9;
+        /// Set on a non-present entry whose page is in swap. The address
+        /// field holds the swap slot instead of a physical page.
+        const SWAPPED = 1 << 10;
+        const UNBACKED = 1 << 11;
+
+        /// In a huge page entry, the high bit of the PAT index
+        const HUGE_PAT = 1 << 12;
+        const PROTECTION_KEY = 0xF << 59;
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
+impl PageFlags {
+    pub fn with_protection_key(self, key: u8) -> Self {
+        assert!(key < 16, "protection keys are 4 bits");
+        let key = Self::from_bits_truncate((key as usize) << 59);
+        (self - Self::PROTECTION_KEY) | key
+    }
+
+    pub fn protection_key(self) -> u8 {
+        ((self & Self::PROTECTION_KEY).bits() >> 59) as u8
+    }
+}
 
 impl PhysicalAddress {
     pub fn page(self) -> PhysicalPage {
@@ -64,20 +138,34 @@ impl PhysicalAddress {
         self.0 & PAGE_OFFSET_MASK
     }
 
+    /// The address of this physical address in the direct map. Panics if
+    /// it is not covered, rather than letting the access fault somewhere
+    /// unrelated.
+    pub fn direct_map(self) -> usize {
+        let end = direct_map_end();
+        if self.0 >= end {
+            panic!(
+                "physical address {:#x} is outside the direct map (ends {:#x})",
+                self.0, end
+            );
+        }
+        self.0 + PHY_OFFSET
+    }
+
     pub unsafe fn read_phy<T: Copy>(self) -> T {
-        *((self.0 + PHY_OFFSET) as *const T)
+        *(self.direct_map() as *const T)
     }
 
     pub unsafe fn write_phy<T>(self, v: T) {
-        *((self.0 + PHY_OFFSET) as *mut T) = v
+        *(self.direct_map() as *mut T) = v
     }
 
     pub unsafe fn as_ref<T>(self) -> &'static T {
-        &*((self.0 + PHY_OFFSET) as *const T)
+        &*(self.direct_map() as *const T)
     }
 
     pub unsafe fn as_mut<T>(self) -> &'static mut T {
-        &mut *((self.0 + PHY_OFFSET) as *mut T)
+        &mut *(self.direct_map() as *mut T)
     }
 }
 
@@ -106,83 +194,197 @@ impl PhysicalPage {
     }
 }
 
-struct PhysicalRangeIter {
-    start: usize,
-    end: usize,
-    cursor: usize,
-}
+/// Both range types are half-open, `start..end`. A range with
+/// `start >= end` is empty.
+macro_rules! address_range {
+    ($range:ident, $address:ident, $page:ident) => {
+        impl $range {
+            pub fn new(start: usize, end: usize) -> Self {
+                Self { start, end }
+            }
 
-impl Iterator for PhysicalRangeIter {
-    type Item = PhysicalAddress;
+            pub fn from_range(r: Range<usize>) -> Self {
+                Self::new(r.start, r.end)
+            }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.cursor > self.end {
-            None
-        } else {
-            self.cursor += 1;
-            Some(PhysicalAddress(self.cursor))
+            pub fn empty() -> Self {
+                Self::new(0, 0)
+            }
+
+            pub fn len(self) -> usize {
+                self.end.saturating_sub(self.start)
+            }
+
+            pub fn is_empty(self) -> bool {
+                self.start >= self.end
+            }
+
+            pub fn contains(self, address: usize) -> bool {
+                address >= self.start && address < self.end
+            }
+
+            pub fn overlaps(self, other: Self) -> bool {
+                !self.intersect(other).is_empty()
+            }
+
+            /// The part of the range that is also in `other`
+            pub fn intersect(self, other: Self) -> Self {
+                let r = Self::new(
+                    self.start.max(other.start),
+                    self.end.min(other.end),
+                );
+                if r.is_empty() {
+                    Self::empty()
+                } else {
+                    r
+                }
+            }
+
+            /// The parts of the range below and above `other`. Either may
+            /// be empty.
+            pub fn subtract(self, other: Self) -> (Self, Self) {
+                if self.is_empty() || !self.overlaps(other) {
+                    return (self, Self::empty());
+                }
+                let below = Self::new(self.start, other.start);
+                let above = Self::new(other.end, self.end);
+                let below = if below.is_empty() {
+                    Self::empty()
+                } else {
+                    below
+                };
+                let above = if above.is_empty() {
+                    Self::empty()
+                } else {
+                    above
+                };
+                (below, above)
+            }
+
+            /// Shrink the range to the `align` boundaries inside it
+            pub fn align_in(self, align: usize) -> Self {
+                let r = Self::new(
+                    round_up(self.start, align),
+                    round_down(self.end, align),
+                );
+                if r.is_empty() {
+                    Self::empty()
+                } else {
+                    r
+                }
+            }
+
+            /// Grow the range to the `align` boundaries around it
+            pub fn align_out(self, align: usize) -> Self {
+                if self.is_empty() {
+                    return Self::empty();
+                }
+                Self::new(
+                    round_down(self.start, align),
+                    round_up(self.end, align),
+                )
+            }
+
+            /// Split the range into runs of pages, using the largest of
+            /// `page_sizes` that is aligned and fits at each point. Each
+            /// item is a run and the size of the pages in it. The range has
+            /// to be aligned to the smallest page size.
+            pub fn page_runs(self, page_sizes: &[usize]) -> PageRuns<'_, Self> {
+                PageRuns {
+                    cursor: self.start,
+                    end: if self.is_empty() {
+                        self.start
+                    } else {
+                        self.end
+                    },
+                    page_sizes,
+                    _range: core::marker::PhantomData,
+                }
+            }
+
+            /// Every address in the range
+            pub fn iter(self) -> impl Iterator<Item = $address> {
+                (self.start..self.end).map($address)
+            }
+
+            /// Every page that overlaps the range
+            pub fn pages(self) -> impl Iterator<Item = $page> {
+                let r = self.align_out(PAGE_SIZE);
+                (r.start..r.end).step_by(PAGE_SIZE).map($page)
+            }
         }
-    }
+
+        impl From<Range<usize>> for $range {
+            fn from(r: Range<usize>) -> Self {
+                Self::from_range(r)
+            }
+        }
+
+        impl fmt::Debug for $range {
+            fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.start.fmt(fmt)?;
+                write!(fmt, "..")?;
+                self.end.fmt(fmt)?;
+                Ok(())
+            }
+        }
+
+        impl<'a> Iterator for PageRuns<'a, $range> {
+            type Item = ($range, usize);
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let (start, end, size) = self.next_run()?;
+                Some(($range::new(start, end), size))
+            }
+        }
+    };
 }
 
-struct PhysicalRangePages {
-    start: usize,
-    end: usize,
+address_range!(PhysicalRange, PhysicalAddress, PhysicalPage);
+address_range!(VirtualRange, VirtualAddress, VirtualAddress);
+
+pub struct PageRuns<'a, R> {
     cursor: usize,
+    end: usize,
+    page_sizes: &'a [usize],
+    _range: core::marker::PhantomData<R>,
 }
 
-impl Iterator for PhysicalRangePages {
-    type Item = PhysicalPage;
+impl<'a, R> PageRuns<'a, R> {
+    fn fits(&self, at: usize, size: usize) -> bool {
+        at % size == 0 && at.checked_add(size).map_or(false, |e| e <= self.end)
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.cursor > self.end {
-            None
-        } else {
-            self.cursor += PAGE_SIZE;
-            Some(PhysicalPage(self.cursor))
+    fn next_run(&mut self) -> Option<(usize, usize, usize)> {
+        let start = self.cursor;
+        let size = self
+            .page_sizes
+            .iter()
+            .copied()
+            .filter(|&size| self.fits(start, size))
+            .max()?;
+
+        // The run of `size` pages goes until the end, or until a larger
+        // page would fit.
+        let mut end = round_down(self.end, size);
+        for &larger in self.page_sizes.iter().filter(|&&s| s > size) {
+            let boundary = round_up(start + 1, larger);
+            if self.fits(boundary, larger) {
+                end = end.min(boundary);
+            }
         }
+
+        self.cursor = end;
+        Some((start, end, size))
     }
 }
 
 impl PhysicalRange {
-    pub fn from_range(r: Range<usize>) -> Self {
-        Self {
-            start: r.start,
-            end: r.end,
-        }
-    }
-
     pub fn from_multiboot_area(area: &multiboot2::MemoryArea) -> Self {
         let start = area.start_address() as usize;
         let end = area.end_address() as usize;
         PhysicalRange { start, end }
     }
-
-    pub fn iter(self) -> impl Iterator<Item = PhysicalAddress> {
-        PhysicalRangeIter {
-            start: self.start,
-            end: self.end,
-            cursor: self.start,
-        }
-    }
-
-    pub fn pages(self) -> impl Iterator<Item = PhysicalPage> {
-        let start = round_down(self.start, PAGE_SIZE);
-        PhysicalRangePages {
-            start,
-            end: self.end,
-            cursor: start,
-        }
-    }
-}
-
-impl fmt::Debug for PhysicalRange {
-    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.start.fmt(fmt)?;
-        write!(fmt, "..")?;
-        self.end.fmt(fmt)?;
-        Ok(())
-    }
 }
 
 impl PageTable {
@@ -201,18 +403,56 @@ impl PageTable {
         unsafe { Self::entry_address(root, index).as_mut() }
     }
 
-    fn make_next_table(p: &mut PageTableEntry, kernel: bool) {
-        let flags = if kernel {
-            PAGE_TABLE_FLAGS
-        } else {
-            PAGE_TABLE_FLAGS | PAGE_USERMODE
-        };
-        *p = PageTableEntry(phy_map::alloc_zero().0 | flags);
-        dprintln!("make_next_table: {:x?} -> {:x?}", p, (*p).0);
+    fn make_next_table(
+        p: &mut PageTableEntry,
+        kernel: bool,
+    ) -> Result<(), PagingError> {
+        let mut flags = PageFlags::PRESENT | PageFlags::WRITEABLE;
+        if !kernel {
+            flags |= PageFlags::USERMODE;
+        }
+        let table =
+            phy_map::try_alloc_zero().ok_or(PagingError::OutOfMemory)?;
+        *p = PageTableEntry::from_page_flags(table.page(), flags);
+        trace!("make_next_table: {:x?} -> {:x?}", p, (*p).0);
+        Ok(())
     }
 
     fn offset(v: VirtualAddress, level: usize) -> usize {
-        (v.0 >> (12 + (level - 1) * 9)) & 0xFFF
+        (v.0 >> (12 + (level - 1) * 9)) & 0x1FF
+    }
+
+    /// The table level whose entries map pages of `page_size` bytes.
+    fn level_for_size(page_size: usize) -> usize {
+        match page_size {
+            PAGE_SIZE => 1,
+            HUGE_PAGE_SIZE => 2,
+            GIANT_PAGE_SIZE => 3,
+            _ => panic!("invalid page size {:#x}", page_size),
+        }
+    }
+
+    pub fn current() -> Self {
+        PageTable(PhysicalPage::from_usize(x86::read_cr3()))
+    }
+
+    pub fn is_current(&self) -> bool {
+        PhysicalPage::from_usize(x86::read_cr3()) == self.0
+    }
+
+    /// Switch to this address space.
+    pub unsafe fn activate(&self) {
+        x86::write_cr3(pcid::cr3_for(self.0));
+    }
+
+    /// Drop any TLB entry for `v` in this address space, after its mapping
+    /// was changed.
+    pub fn invalidate(&self, v: VirtualAddress) {
+        if self.is_current() {
+            x86::invlpg(v.0);
+        } else {
+            pcid::invalidate_other(self.0, v.0);
+        }
     }
 
     fn pte_mut_recursive(
@@ -220,65 +460,279 @@ impl PageTable {
         root: PhysicalPage,
         v: VirtualAddress,
         level: usize,
+        target: usize,
         create: bool,
     ) -> Result<&mut PageTableEntry, PagingError> {
         let offset = Self::offset(v, level);
         let entry = Self::entry_mut(root, offset);
-        dprintln!("pte_mut_recursive: p{:#x} -> v{:#x} (level {}) (create {}) (offset {}) (entry {:x})",
+        trace!("pte_mut_recursive: p{:#x} -> v{:#x} (level {}) (create {}) (offset {}) (entry {:x})",
             root.0, v.0, level, create, offset, entry.0);
-        if level == 1 {
+        if level == target {
             return Ok(entry);
         }
         if !entry.present() {
             if create {
-                Self::make_next_table(entry, v.is_higher_half());
+                Self::make_next_table(entry, v.is_higher_half())?;
             } else {
-                return Err(PagingError::Other("Page Not Present"));
+                return Err(PagingError::NotMapped);
+            }
+        }
+        if entry.is_huge() {
+            return Err(PagingError::HugePageConflict);
+        }
+        self.pte_mut_recursive(entry.deref(), v, level - 1, target, create)
+    }
+
+    /// Find the physical address `v` maps to, along with the flags of the
+    /// mapping. WRITEABLE and USERMODE are only reported if they are set at
+    /// every level, and NO_EXECUTE if it is set at any level, since that is
+    /// what the hardware enforces.
+    pub fn translate(
+        &self,
+        v: VirtualAddress,
+    ) -> Option<(PhysicalAddress, PageFlags)> {
+        let mut table = self.0;
+        let hierarchical = PageFlags::WRITEABLE | PageFlags::USERMODE;
+        let mut allowed = hierarchical;
+        let mut no_execute = PageFlags::empty();
+
+        for level in (1..=paging_levels()).rev() {
+            let entry = Self::entry(table, Self::offset(v, level));
+            if !entry.present() {
+                return None;
+            }
+            allowed &= entry.flags();
+            no_execute |= entry.flags() & PageFlags::NO_EXECUTE;
+            if level == 1 || entry.is_huge() {
+                let size = PAGE_SIZE << ((level - 1) * 9);
+                let base = entry.deref().0 & !(size - 1);
+                let flags =
+                    (entry.flags() - hierarchical) | allowed | no_execute;
+                return Some((
+                    PhysicalAddress(base + (v.0 & (size - 1))),
+                    flags,
+                ));
+            }
+            table = entry.deref();
+        }
+        None
+    }
+
+    fn visit_leaves(
+        root: PhysicalPage,
+        level: usize,
+        base: usize,
+        range: &Range<usize>,
+        f: &mut dyn FnMut(VirtualAddress, &mut PageTableEntry) -> bool,
+    ) -> bool {
+        let span = PAGE_SIZE << ((level - 1) * 9);
+        for index in 0..512 {
+            let start = base + index * span;
+            if start + span <= range.start {
+                continue;
+            }
+            if start >= range.end {
+                break;
+            }
+            let entry = Self::entry_mut(root, index);
+            if !entry.present() {
+                continue;
+            }
+            if level == 1 {
+                if !f(VirtualAddress(start), entry) {
+                    return false;
+                }
+            } else if !entry.is_huge()
+                && !Self::visit_leaves(
+                    entry.deref(),
+                    level - 1,
+                    start,
+                    range,
+                    f,
+                )
+            {
+                return false;
             }
         }
-        self.pte_mut_recursive(entry.deref(), v, level - 1, create)
+        true
+    }
+
+    /// Call `f` on every present 4KiB page mapped in `range`, which must be
+    /// in the lower half, stopping early if it returns false.
+    pub fn for_each_page_mut(
+        &mut self,
+        range: Range<usize>,
+        mut f: impl FnMut(VirtualAddress, &mut PageTableEntry) -> bool,
+    ) {
+        assert!(!VirtualAddress(range.end - 1).is_higher_half());
+        Self::visit_leaves(self.0, paging_levels(), 0, &range, &mut f);
     }
 
     pub fn pte(&self, v: VirtualAddress) -> PageTableEntry {
-        self.pte_mut_recursive(self.0, v, 4, false)
+        self.pte_mut_recursive(self.0, v, paging_levels(), 1, false)
             .map(|p| *p)
             .unwrap_or(PageTableEntry::nil())
     }
 
-    fn pte_mut(&mut self, v: VirtualAddress) -> &mut PageTableEntry {
-        &mut *self.pte_mut_recursive(self.0, v, 4, true).unwrap()
+    /// The 4KiB page table entry for `v`, creating any missing page tables
+    /// on the way to it.
+    pub fn pte_mut(
+        &mut self,
+        v: VirtualAddress,
+    ) -> Result<&mut PageTableEntry, PagingError> {
+        self.pte_mut_recursive(self.0, v, paging_levels(), 1, true)
+    }
+
+    pub fn map(
+        &mut self,
+        v: VirtualAddress,
+        p: PhysicalPage,
+        flags: PageFlags,
+    ) -> Result<(), PagingError> {
+        self.map_sized(v, p, PAGE_SIZE, flags)
     }
 
-    pub fn map(&mut self, v: VirtualAddress, p: PhysicalPage, flags: usize) {
-        *self.pte_mut(v) =
-            PageTableEntry::from_page_flags(p, flags | PAGE_PRESENT);
+    /// Map a `page_size` page (4KiB, 2MiB or 1GiB). Both addresses must be
+    /// aligned to the page size.
+    pub fn map_sized(
+        &mut self,
+        v: VirtualAddress,
+        p: PhysicalPage,
+        page_size: usize,
+        flags: PageFlags,
+    ) -> Result<(), PagingError> {
+        if v.0 % page_size != 0 || p.0 % page_size != 0 {
+            return Err(PagingError::Unaligned);
+        }
+
+        let level = Self::level_for_size(page_size);
+        let mut flags = flags | PageFlags::PRESENT;
+        if level > 1 {
+            flags |= PageFlags::ISHUGE;
+        }
+        let pte =
+            self.pte_mut_recursive(self.0, v, paging_levels(), level, true)?;
+        if !pte.is_nil() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        *pte = PageTableEntry::from_page_flags(p, flags);
+        Ok(())
     }
 
-    pub fn unmap(&mut self, v: VirtualAddress) {
-        *self.pte_mut(v) = PageTableEntry::nil();
+    pub fn unmap(&mut self, v: VirtualAddress) -> Result<(), PagingError> {
+        let pte =
+            self.pte_mut_recursive(self.0, v, paging_levels(), 1, false)?;
+        if pte.is_nil() {
+            return Err(PagingError::NotMapped);
+        }
+        *pte = PageTableEntry::nil();
+        Ok(())
     }
 
-    pub fn edit_flags(&mut self, v: VirtualAddress, flags: usize) {
-        let pte_mut = self.pte_mut(v);
-        *pte_mut = (*pte_mut & PAGE_ADDR_MASK) | flags;
+    /// Replace the huge page that maps `v` with a table of the next size
+    /// down, mapping the same memory with the same flags, so part of it can
+    /// be mapped differently. Returns false if `v` is already mapped by a
+    /// 4KiB page. The old TLB entry still translates the same way, so
+    /// nothing is flushed.
+    fn split_huge_page(
+        &mut self,
+        v: VirtualAddress,
+    ) -> Result<bool, PagingError> {
+        let mut table = self.0;
+        for level in (2..=paging_levels()).rev() {
+            let entry = Self::entry_mut(table, Self::offset(v, level));
+            if !entry.present() {
+                return Err(PagingError::NotMapped);
+            }
+            if !entry.is_huge() {
+                table = entry.deref();
+                continue;
+            }
+            let size = PAGE_SIZE << ((level - 1) * 9);
+            let smaller = size >> 9;
+            let base = entry.deref().0 & !(size - 1);
+            let flags = entry.flags() - PageFlags::ISHUGE;
+            let leaf_flags = if level == 2 {
+                flags
+            } else {
+                flags | PageFlags::ISHUGE
+            };
+            let next = phy_map::try_alloc_zero()
+                .ok_or(PagingError::OutOfMemory)?
+                .page();
+            for index in 0..512 {
+                *Self::entry_mut(next, index) = PageTableEntry::from_page_flags(
+                    PhysicalPage(base + index * smaller),
+                    leaf_flags,
+                );
+            }
+            *entry = PageTableEntry::from_page_flags(next, flags);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Replace the flags of the page mapped at `v`. The page stays present.
+    pub fn edit_flags(
+        &mut self,
+        v: VirtualAddress,
+        flags: PageFlags,
+    ) -> Result<(), PagingError> {
+        let pte =
+            self.pte_mut_recursive(self.0, v, paging_levels(), 1, false)?;
+        if !pte.present() {
+            return Err(PagingError::NotMapped);
+        }
+        *pte = PageTableEntry::from_page_flags(
+            pte.deref(),
+            flags | PageFlags::PRESENT,
+        );
+        Ok(())
     }
 }
 
 impl PageTableEntry {
-    fn from_page_flags(p: PhysicalPage, f: usize) -> Self {
-        Self(p.0 | f)
+    pub fn from_page_flags(p: PhysicalPage, f: PageFlags) -> Self {
+        Self(p.0 | f.bits())
     }
 
-    fn nil() -> Self {
+    pub fn nil() -> Self {
         Self(0)
     }
 
-    fn deref(self) -> PhysicalPage {
+    pub fn deref(self) -> PhysicalPage {
         PhysicalPage(self.0 & PAGE_ADDR_MASK)
     }
 
-    fn present(self) -> bool {
-        self.0 & PAGE_PRESENT != 0
+    pub fn flags(self) -> PageFlags {
+        PageFlags::from_bits_truncate(self.0 & PAGE_FLAGS_MASK)
+    }
+
+    /// A non-present entry recording that the page is in swap `slot`. The
+    /// other flags are kept so the page comes back with the same access.
+    pub fn swapped(slot: usize, flags: PageFlags) -> Self {
+        let flags = (flags - PageFlags::PRESENT) | PageFlags::SWAPPED;
+        Self((slot << 12) | flags.bits())
+    }
+
+    pub fn swap_slot(self) -> Option<usize> {
+        if !self.present() && self.flags().contains(PageFlags::SWAPPED) {
+            Some((self.0 & PAGE_ADDR_MASK) >> 12)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_nil(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn present(self) -> bool {
+        self.flags().contains(PageFlags::PRESENT)
+    }
+
+    fn is_huge(self) -> bool {
+        self.flags().contains(PageFlags::ISHUGE)
     }
 
     // writeable(), usermode(), etc are harder to do correctly, since
@@ -300,8 +754,412 @@ impl BitAnd<usize> for PageTableEntry {
     }
 }
 
+/// The address space of a user program: a page table of its own for the
+/// lower half, sharing the kernel's upper half. The user pages and the page
+/// tables that map them are freed when it's dropped.
+///
+/// The kernel's top level entries are copied when it is created, so the
+/// kernel must not add new top level entries after user programs start.
+/// Dropping the active address space switches to the kernel's page table,
+/// and nothing may switch back to it after that.
+#[derive(Debug)]
+pub struct AddressSpace {
+    table: PageTable,
+    /// Unmapped before the page tables are freed
+    shared: Vec<SharedMapping>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, PagingError> {
+        let root = phy_map::try_alloc_zero()
+            .ok_or(PagingError::OutOfMemory)?
+            .page();
+        let kernel = kernel_table().0;
+        for index in 256..512 {
+            *PageTable::entry_mut(root, index) =
+                PageTable::entry(kernel, index);
+        }
+        Ok(Self {
+            table: PageTable(root),
+            shared: Vec::new(),
+        })
+    }
+
+    pub fn table(&self) -> &PageTable {
+        &self.table
+    }
+
+    pub fn table_mut(&mut self) -> &mut PageTable {
+        &mut self.table
+    }
+
+    pub fn root(&self) -> PhysicalPage {
+        self.table.0
+    }
+
+    /// Switch to this address space. It must not be dropped while it is
+    /// active.
+    pub unsafe fn activate(&self) {
+        self.table.activate();
+    }
+
+    /// Map all of `memory` at `base`, for as long as this address space
+    /// lives or until unmap_shared.
+    pub fn map_shared(
+        &mut self,
+        memory: &SharedMemory,
+        base: VirtualAddress,
+        flags: PageFlags,
+    ) -> Result<(), PagingError> {
+        let mapping = memory.map(&mut self.table, base, flags)?;
+        self.shared.push(mapping);
+        Ok(())
+    }
+
+    /// Remove the shared mapping at `base`. Returns false if there isn't
+    /// one.
+    pub fn unmap_shared(&mut self, base: VirtualAddress) -> bool {
+        let before = self.shared.len();
+        self.shared.retain(|mapping| mapping.base() != base);
+        self.shared.len() != before
+    }
+
+    /// Map a newly allocated zeroed page at `v`.
+    pub fn map_zeroed(
+        &mut self,
+        v: VirtualAddress,
+        flags: PageFlags,
+    ) -> Result<(), PagingError> {
+        let page = phy_map::try_alloc_zero().ok_or(PagingError::OutOfMemory)?;
+        let result = self.table.map(v, page.page(), flags);
+        if result.is_err() {
+            phy_map::free(page);
+        }
+        result
+    }
+
+    /// Call `f` on each piece of the `len` bytes at `v`, through the direct
+    /// map, along with the offset of the piece. This works whether or not
+    /// the address space is active, but every page must be present.
+    fn with_memory(
+        &self,
+        v: VirtualAddress,
+        len: usize,
+        mut f: impl FnMut(&mut [u8], usize),
+    ) -> Result<(), PagingError> {
+        let mut done = 0;
+        while done < len {
+            let address =
+                v.0.checked_add(done).ok_or(PagingError::NotMapped)?;
+            let (p, _) = self
+                .table
+                .translate(VirtualAddress(address))
+                .ok_or(PagingError::NotMapped)?;
+            let count = (PAGE_SIZE - p.page_offset()).min(len - done);
+            let memory = unsafe {
+                slice::from_raw_parts_mut(p.direct_map() as *mut u8, count)
+            };
+            f(memory, done);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Copy `bytes` to `v` in this address space.
+    pub fn write(
+        &self,
+        v: VirtualAddress,
+        bytes: &[u8],
+    ) -> Result<(), PagingError> {
+        self.with_memory(v, bytes.len(), |memory, offset| {
+            memory.copy_from_slice(&bytes[offset..offset + memory.len()])
+        })
+    }
+
+    /// Set the `len` bytes at `v` in this address space to `value`.
+    pub fn fill(
+        &self,
+        v: VirtualAddress,
+        len: usize,
+        value: u8,
+    ) -> Result<(), PagingError> {
+        self.with_memory(v, len, |memory, _| {
+            for byte in memory {
+                *byte = value;
+            }
+        })
+    }
+}
+
+/// Free the user pages mapped by `table`, a `level` page table in the
+/// lower half, and then the table itself.
+fn free_user_table(table: PhysicalPage, level: usize) {
+    for index in 0..512 {
+        let entry = PageTable::entry(table, index);
+        if level == 1 {
+            if entry.present() {
+                phy_map::free(entry.deref().base_address());
+            } else if let Some(slot) = entry.swap_slot() {
+                swap::discard(slot);
+            }
+        } else if entry.present() && !entry.is_huge() {
+            free_user_table(entry.deref(), level - 1);
+        }
+    }
+    phy_map::free(table.base_address());
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // The shared pages are unmapped through the page tables freed
+        // below, and their references are the mappings' to give back
+        self.shared.clear();
+        if self.table.is_current() {
+            unsafe { kernel_table().activate() };
+        }
+        let root = self.table.0;
+        for index in 0..256 {
+            let entry = PageTable::entry(root, index);
+            if entry.present() && !entry.is_huge() {
+                free_user_table(entry.deref(), paging_levels() - 1);
+            }
+        }
+        pcid::release(root);
+        phy_map::free(root.base_address());
+    }
+}
+
 impl VirtualAddress {
-    fn is_higher_half(self) -> bool {
-        self.0 > 0x8000_0000_0000
+    /// Whether this is outside the lower (user) half. Non-canonical
+    /// addresses count as higher half, so a range that passes
+    /// `!is_higher_half()` is always safe to give to user mode.
+    pub fn is_higher_half(self) -> bool {
+        self.0 >= lower_half_end()
+    }
+
+    /// Whether the bits above the implemented virtual address width are a
+    /// sign extension of the top implemented bit.
+    pub fn is_canonical(self) -> bool {
+        let shift = 64 - virtual_address_bits();
+        (((self.0 << shift) as isize) >> shift) as usize == self.0
     }
 }
+
+/// Read whether boot.asm enabled 5-level paging. This must run before
+/// anything walks a page table.
+pub fn paging_init() {
+    let levels = if x86::read_cr4() & x86::CR4_LA57 != 0 {
+        5
+    } else {
+        4
+    };
+    PAGING_LEVELS.store(levels, Ordering::Relaxed);
+    KERNEL_TABLE.store(PageTable::current().0 .0, Ordering::Relaxed);
+    info!("{} levels", levels);
+}
+
+/// The page table the kernel booted on, for when no user address space is
+/// needed
+pub fn kernel_table() -> PageTable {
+    PageTable(PhysicalPage(KERNEL_TABLE.load(Ordering::Relaxed)))
+}
+
+pub fn paging_levels() -> usize {
+    PAGING_LEVELS.load(Ordering::Relaxed)
+}
+
+/// 48 with 4-level paging, 57 with 5-level paging
+pub fn virtual_address_bits() -> usize {
+    12 + 9 * paging_levels()
+}
+
+/// The end of the lower half of the address space, one past the highest
+/// address user mode can use.
+pub fn lower_half_end() -> usize {
+    1 << (virtual_address_bits() - 1)
+}
+
+pub fn direct_map_end() -> usize {
+    DIRECT_MAP_END.load(Ordering::Relaxed)
+}
+
+/// Extend the boot direct map at PHY_OFFSET to cover every memory area
+/// reported by the bootloader, using the largest page size the CPU has.
+pub fn direct_map_init(areas: multiboot2::MemoryAreaIter<'_>) {
+    let top = areas
+        .map(|area| area.end_address() as usize)
+        .max()
+        .unwrap_or(0);
+
+    let page_size = if x86::has_1g_pages() {
+        GIANT_PAGE_SIZE
+    } else {
+        HUGE_PAGE_SIZE
+    };
+
+    let start = direct_map_end();
+    let end = round_up(top, page_size);
+    let mut table = PageTable::current();
+
+    for p in (start..end).step_by(page_size) {
+        table
+            .map_sized(
+                VirtualAddress(p + PHY_OFFSET),
+                PhysicalPage(p),
+                page_size,
+                PageFlags::WRITEABLE
+                    | PageFlags::GLOBAL
+                    | PageFlags::NO_EXECUTE,
+            )
+            .expect("failed to extend the direct map");
+    }
+
+    if end > start {
+        DIRECT_MAP_END.store(end, Ordering::Relaxed);
+    }
+
+    info!(
+        "direct map: {:#x}..{:#x} ({:#x} byte pages)",
+        0,
+        direct_map_end(),
+        page_size
+    );
+}
+
+/// Map device registers at their direct map address, uncached, and return
+/// the address of the start of `range`. The direct map can cover device
+/// memory with cached huge pages, since it runs up to the end of the
+/// highest memory area; those are split so the device pages get 4KiB
+/// mappings of their own.
+pub fn map_mmio(range: PhysicalRange) -> Result<VirtualAddress, PagingError> {
+    let flags = PageFlags::WRITEABLE
+        | PageFlags::CACHE_DISABLE
+        | PageFlags::WRITE_THROUGH
+        | PageFlags::GLOBAL
+        | PageFlags::NO_EXECUTE;
+    let mut table = PageTable::current();
+    for page in range.pages() {
+        let v = VirtualAddress(page.0 + PHY_OFFSET);
+        match table.translate(v) {
+            None => table.map(v, page, flags)?,
+            Some((_, old)) if old.contains(PageFlags::CACHE_DISABLE) => {}
+            Some(_) => {
+                while table.split_huge_page(v)? {}
+                table.edit_flags(v, flags)?;
+                // This also drops a TLB entry for the huge page, which is
+                // global and so not covered by the PCID
+                x86::invlpg(v.0);
+            }
+        }
+    }
+    Ok(VirtualAddress(range.start + PHY_OFFSET))
+}
+
+/// Unmap a page of the kernel image below a stack, so running off the end
+/// of the stack faults instead of corrupting what is below it.
+pub fn set_guard_page(v: VirtualAddress) {
+    let mut table = PageTable::current();
+    match table.unmap(v) {
+        // Kernel image pages aren't global, so other PCIDs can have the
+        // page cached too
+        Ok(()) => pcid::invalidate_all(v.0),
+        Err(error) => warn!("can't unmap guard page {:#x}: {:?}", v.0, error),
+    }
+}
+
+/// Map a page unmapped by set_guard_page back, before its memory is
+/// reused.
+pub fn clear_guard_page(v: VirtualAddress) {
+    let mut table = PageTable::current();
+    if table.translate(v).is_some() {
+        return;
+    }
+    table
+        .map(
+            v,
+            PhysicalPage::from_kernel_pointer(v.0),
+            PageFlags::WRITEABLE,
+        )
+        .expect("failed to map a guard page back");
+    pcid::invalidate_all(v.0);
+}
+
+/// Most words the shell's peek prints at once.
+const PEEK_MAX_WORDS: usize = 512;
+
+/// The direct map address of the aligned word at `v`, for the shell's peek
+/// and poke, which must not fault on a bad address.
+fn word_at(v: VirtualAddress) -> Result<*mut u64, &'static str> {
+    if v.0 % size_of::<u64>() != 0 {
+        return Err("address must be 8 byte aligned");
+    }
+    match PageTable::current().translate(v) {
+        Some((p, _)) if p.0 < direct_map_end() => {
+            Ok(p.direct_map() as *mut u64)
+        }
+        Some(_) => Err("address is outside the direct map"),
+        None => Err("address is not mapped"),
+    }
+}
+
+pub fn register_commands() {
+    shell::register(Command {
+        name: "translate",
+        usage: "translate <address>",
+        help: "show the physical address and flags a virtual address maps to",
+        run: |args| {
+            let v = VirtualAddress(shell::parse_number(
+                args.first().ok_or("missing address")?,
+            )?);
+            match PageTable::current().translate(v) {
+                Some((p, flags)) => {
+                    println!("{:#x} -> {:#x} {:?}", v.0, p.0, flags);
+                }
+                None => {
+                    println!("{:#x} is not mapped", v.0);
+                }
+            }
+            Ok(())
+        },
+    });
+    shell::register(Command {
+        name: "peek",
+        usage: "peek <address> [words]",
+        help: "print 64-bit words from memory",
+        run: |args| {
+            let base =
+                shell::parse_number(args.first().ok_or("missing address")?)?;
+            let count = match args.get(1) {
+                Some(count) => shell::parse_number(count)?,
+                None => 1,
+            };
+            if count > PEEK_MAX_WORDS {
+                return Err("too many words");
+            }
+            for i in 0..count {
+                let v = i
+                    .checked_mul(size_of::<u64>())
+                    .and_then(|offset| base.checked_add(offset))
+                    .map(VirtualAddress)
+                    .ok_or("address out of range")?;
+                let word = unsafe { *word_at(v)? };
+                println!("{:#018x}: {:#018x}", v.0, word);
+            }
+            Ok(())
+        },
+    });
+    shell::register(Command {
+        name: "poke",
+        usage: "poke <address> <value>",
+        help: "write a 64-bit word to memory",
+        run: |args| {
+            let v = VirtualAddress(shell::parse_number(
+                args.first().ok_or("missing address")?,
+            )?);
+            let value =
+                shell::parse_number(args.get(1).ok_or("missing value")?)?;
+            unsafe { *word_at(v)? = value as u64 };
+            Ok(())
+        },
+    });
+}
@@ -0,0 +1,193 @@
+use crate::memory::PhysicalPage;
+use crate::sync::Mutex;
+use crate::x86;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// PCIDs are 12 bits. PCID 0 stays with the boot page table, which is
+/// what was loaded when PCIDs were turned on.
+const PCID_COUNT: usize = 4096;
+
+#[derive(Copy, Clone, Debug)]
+struct Assignment {
+    pcid: u16,
+    /// The TLB may still hold entries for this PCID from an address space
+    /// that had it before, or from changes made while this address space
+    /// wasn't loaded. The next CR3 load has to flush instead of keeping
+    /// them.
+    stale: bool,
+}
+
+struct Pcids {
+    owners: Vec<Option<PhysicalPage>>,
+    assigned: BTreeMap<PhysicalPage, Assignment>,
+    /// Where to start looking for a PCID to take back when they run out
+    hand: usize,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static INVPCID: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Only locked with interrupts disabled, since the scheduler switches
+    /// address spaces
+    static ref PCIDS: Mutex<Pcids> = Mutex::new(Pcids::new());
+}
+
+impl Pcids {
+    fn new() -> Self {
+        Self {
+            owners: vec![None; PCID_COUNT],
+            assigned: BTreeMap::new(),
+            hand: 1,
+        }
+    }
+
+    fn give(&mut self, root: PhysicalPage, pcid: u16, stale: bool) {
+        self.owners[pcid as usize] = Some(root);
+        self.assigned.insert(root, Assignment { pcid, stale });
+    }
+
+    fn take_back(&mut self, pcid: u16) {
+        if let Some(root) = self.owners[pcid as usize].take() {
+            self.assigned.remove(&root);
+        }
+    }
+
+    /// Find a free PCID, taking one back from another address space if
+    /// they are all in use. The current address space is never picked.
+    fn pick(&mut self) -> u16 {
+        if let Some(free) = self.owners.iter().skip(1).position(|o| o.is_none())
+        {
+            return free as u16 + 1;
+        }
+
+        let current = PhysicalPage::from_usize(x86::read_cr3());
+        loop {
+            let pcid = self.hand;
+            self.hand = if self.hand + 1 == PCID_COUNT {
+                1
+            } else {
+                self.hand + 1
+            };
+            if self.owners[pcid] != Some(current) {
+                self.take_back(pcid as u16);
+                return pcid as u16;
+            }
+        }
+    }
+
+    fn assign(&mut self, root: PhysicalPage) -> &mut Assignment {
+        if !self.assigned.contains_key(&root) {
+            let pcid = self.pick();
+            self.give(root, pcid, true);
+        }
+        self.assigned.get_mut(&root).unwrap()
+    }
+}
+
+/// Turn on PCIDs if the CPU supports them.
+pub fn init() {
+    if !x86::has_pcid() {
+        info!("not supported");
+        return;
+    }
+
+    let boot_root = PhysicalPage::from_usize(x86::read_cr3());
+    PCIDS.lock().give(boot_root, 0, false);
+
+    // CR3 has PCID 0 right now, which is the only time PCIDE can be set.
+    unsafe { x86::write_cr4(x86::read_cr4() | x86::CR4_PCIDE) };
+    ENABLED.store(true, Ordering::Relaxed);
+    INVPCID.store(x86::has_invpcid(), Ordering::Relaxed);
+    info!("enabled (invpcid: {})", x86::has_invpcid());
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The value to load into CR3 to switch to the page table at `root`. This
+/// only keeps the old TLB entries for its PCID if they are known to be
+/// good.
+pub fn cr3_for(root: PhysicalPage) -> usize {
+    if !enabled() {
+        return root.0;
+    }
+
+    x86::without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        let assignment = pcids.assign(root);
+        let noflush = if assignment.stale {
+            0
+        } else {
+            x86::CR3_NOFLUSH
+        };
+        assignment.stale = false;
+        root.0 | assignment.pcid as usize | noflush
+    })
+}
+
+/// Invalidate the TLB entry for `addr` in the address space at `root`,
+/// which is not the current one.
+pub fn invalidate_other(root: PhysicalPage, addr: usize) {
+    if !enabled() {
+        // Without PCIDs, loading the other address space flushes anyway.
+        return;
+    }
+
+    x86::without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        if let Some(assignment) = pcids.assigned.get_mut(&root) {
+            if INVPCID.load(Ordering::Relaxed) {
+                x86::invpcid_address(assignment.pcid, addr);
+            } else {
+                assignment.stale = true;
+            }
+        }
+    })
+}
+
+/// Invalidate the TLB entries for `addr` in every address space, after a
+/// change to a kernel mapping that isn't global. The kernel's page tables
+/// are shared, so every PCID may have cached the old one.
+pub fn invalidate_all(addr: usize) {
+    x86::invlpg(addr);
+    if !enabled() {
+        // Loading any other address space flushes it anyway.
+        return;
+    }
+
+    x86::without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        if INVPCID.load(Ordering::Relaxed) {
+            x86::invpcid_all();
+        } else {
+            for assignment in pcids.assigned.values_mut() {
+                assignment.stale = true;
+            }
+        }
+    })
+}
+
+/// Give up the PCID of the page table at `root`. This has to be called
+/// before the page table is freed, or a new page table allocated at the
+/// same address would inherit its TLB entries.
+pub fn release(root: PhysicalPage) {
+    if !enabled() {
+        return;
+    }
+
+    x86::without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        if let Some(assignment) = pcids.assigned.get(&root) {
+            let pcid = assignment.pcid;
+            pcids.take_back(pcid);
+            if INVPCID.load(Ordering::Relaxed) {
+                x86::invpcid_context(pcid);
+            }
+        }
+    })
+}
@@ -1,8 +1,14 @@
 use crate::memory::{PhysicalAddress, PhysicalPage, PhysicalRange, PAGE_SIZE};
+use crate::shell::{self, Command};
 use crate::sync::RwLock;
-use crate::x86;
+use crate::{swap, x86};
+use alloc::vec;
+use alloc::vec::Vec;
 use core::fmt;
 
+/// How many pages to try to swap out at once when an allocation fails
+const RECLAIM_BATCH: usize = 16;
+
 /// PageRef is designed to resemble a Rust enum, but isn't one to ensure it
 /// fits in a single byte. It does this by having a limited range, supporting
 /// values from 0..252 and using the other representable values for the
@@ -144,7 +150,7 @@ impl PhysicalMap {
     fn alloc(&mut self) -> Option<PhysicalAddress> {
         if let Some(i) = self.usable_index() {
             let page = PhysicalAddress(i * PAGE_SIZE);
-            println!("alloc: {:x?}", page);
+            trace!("alloc: {:x?}", page);
             self.incref(page);
             Some(page)
         } else {
@@ -156,74 +162,149 @@ impl PhysicalMap {
         self.decref(p);
     }
 
-    fn summarize(&self) {
-        let mut in_use = 0;
-        let mut available = 0;
-        let mut leaked = 0;
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
 
-        for (_i, r) in self.map.iter().enumerate() {
-            if r.in_use() {
-                in_use += 0x1000;
+        for r in self.map.iter() {
+            if *r == PageRef::Leak {
+                stats.leaked += PAGE_SIZE;
+            } else if r.in_use() {
+                stats.in_use += PAGE_SIZE;
             } else if r.is_usable() {
-                available += 0x1000;
-            } else if *r == PageRef::Leak {
-                leaked += 0x1000;
+                stats.available += PAGE_SIZE;
             }
         }
-
-        println!(
-            "in_use: {:x} available: {:x} leaked: {:x}",
-            in_use, available, leaked
-        );
+        stats
     }
 }
 
+/// Bytes of physical memory in each state
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    pub in_use: usize,
+    pub available: usize,
+    /// Reserved or kernel memory, which is never handed out
+    pub leaked: usize,
+}
+
 lazy_static! {
     static ref PHYSICAL_MEMORY_MAP: RwLock<PhysicalMap> =
         RwLock::new(PhysicalMap::new());
 }
 
 pub fn map_init(areas: multiboot2::MemoryAreaIter<'_>) {
+    let kernel_range =
+        PhysicalRange::new(x86::kernel_start(), x86::kernel_end());
+    info!("leaking kernel: {:x?}", kernel_range);
+
+    let mut available = Vec::new();
+    let mut reserved = vec![kernel_range];
+
     for area in areas {
         let range = PhysicalRange::from_multiboot_area(area);
         let r = PageRef::from_multiboot(area.typ());
 
-        println!(
+        info!(
             "memory map: {:>10x} {:>10x} {:?}",
             area.start_address(),
             area.size(),
             r
         );
 
-        PHYSICAL_MEMORY_MAP.write().set_range(range, r);
+        if r.is_usable() {
+            available.push(range);
+        } else {
+            reserved.push(range);
+        }
     }
 
-    let kernel_range = PhysicalRange {
-        start: x86::kernel_start(),
-        end: x86::kernel_end(),
-    };
+    let mut map = PHYSICAL_MEMORY_MAP.write();
 
-    println!("Leaking kernel: {:x?}", kernel_range);
+    // Any page that is even partly reserved can't be handed out.
+    for range in &reserved {
+        map.set_range(range.align_out(PAGE_SIZE), PageRef::Leak);
+    }
 
-    PHYSICAL_MEMORY_MAP
-        .write()
-        .set_range(kernel_range, PageRef::Leak);
+    for area in available {
+        let mut pieces = vec![area];
+        for hole in &reserved {
+            pieces = pieces
+                .into_iter()
+                .flat_map(|piece| {
+                    let (below, above) = piece.subtract(*hole);
+                    Some(below).into_iter().chain(Some(above))
+                })
+                .filter(|piece| !piece.is_empty())
+                .collect();
+        }
+        for piece in pieces {
+            map.set_range(piece.align_in(PAGE_SIZE), PageRef::Zero);
+        }
+    }
 }
 
 pub fn leak(r: PhysicalRange) {
     PHYSICAL_MEMORY_MAP.write().set_range(r, PageRef::Leak);
 }
 
+/// Allocate a page, pushing cold user pages out to swap to make room if
+/// memory has run out.
+pub fn try_alloc() -> Option<PhysicalAddress> {
+    if let Some(page) = PHYSICAL_MEMORY_MAP.write().alloc() {
+        return Some(page);
+    }
+    if swap::reclaim(RECLAIM_BATCH) == 0 {
+        return None;
+    }
+    PHYSICAL_MEMORY_MAP.write().alloc()
+}
+
 pub fn alloc() -> PhysicalAddress {
-    PHYSICAL_MEMORY_MAP.write().alloc().expect("Out of memory")
+    try_alloc().expect("Out of memory")
 }
 
-pub fn alloc_zero() -> PhysicalAddress {
-    let page = PHYSICAL_MEMORY_MAP.write().alloc().expect("Out of memory");
+pub fn try_alloc_zero() -> Option<PhysicalAddress> {
+    let page = try_alloc()?;
     unsafe { page.write_phy([0u8; PAGE_SIZE]) };
-    page
+    Some(page)
+}
+
+pub fn alloc_zero() -> PhysicalAddress {
+    try_alloc_zero().expect("Out of memory")
+}
+
+/// The number of references held on the page containing `p`, or None if
+/// it isn't memory the allocator manages.
+pub fn refcount(p: PhysicalAddress) -> Option<usize> {
+    PHYSICAL_MEMORY_MAP.read().map[p.page().index()].count()
+}
+
+pub fn incref(p: PhysicalAddress) {
+    PHYSICAL_MEMORY_MAP.write().incref(p)
 }
 
 pub fn free(p: PhysicalAddress) {
     PHYSICAL_MEMORY_MAP.write().free(p)
 }
+
+pub fn stats() -> Stats {
+    PHYSICAL_MEMORY_MAP.read().stats()
+}
+
+pub fn register_commands() {
+    shell::register(Command {
+        name: "mem",
+        usage: "mem",
+        help: "show physical memory and swap use",
+        run: |_| {
+            let stats = stats();
+            println!("in use:    {:>8} KiB", stats.in_use / 1024);
+            println!("available: {:>8} KiB", stats.available / 1024);
+            println!("leaked:    {:>8} KiB", stats.leaked / 1024);
+            if let Some((used, total)) = swap::usage() {
+                println!("swap:      {} of {} pages", used, total);
+            }
+            Ok(())
+        },
+    });
+}
@@ -0,0 +1,473 @@
+//! Processes. A process owns a user address space, the threads running in
+//! it, a table of handles, its credentials and its place in the process
+//! tree. Threads are still scheduled by thread.rs; each user thread points
+//! back to its process, and kernel threads belong to none.
+//!
+//! When its last thread exits, a process frees its address space and
+//! handles and becomes a zombie holding only its exit status, until it is
+//! reaped by its parent. A process with no parent, because the kernel
+//! started it or its parent exited first, is reaped as soon as it exits.
+
+use crate::elf::{self, ElfError};
+use crate::memory::{AddressSpace, PhysicalPage};
+use crate::shell::{self, Command};
+use crate::thread::{self, WaitQueue};
+use crate::x86;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::RwLock;
+
+pub type Pid = usize;
+pub type ProcessArc = Arc<RwLock<Process>>;
+
+/// The descriptors of the standard handles every process starts with
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+}
+
+/// Something a process has open
+#[derive(Debug, Clone)]
+pub enum Handle {
+    Console,
+}
+
+/// A process's handles, indexed by file descriptor
+#[derive(Debug, Clone, Default)]
+pub struct HandleTable {
+    handles: Vec<Option<Handle>>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Standard input, output and error on the console
+    pub fn with_console() -> Self {
+        Self {
+            handles: vec![Some(Handle::Console); STDERR + 1],
+        }
+    }
+
+    /// Add a handle at the lowest free descriptor, and return that.
+    pub fn insert(&mut self, handle: Handle) -> usize {
+        match self.handles.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.handles[fd] = Some(handle);
+                fd
+            }
+            None => {
+                self.handles.push(Some(handle));
+                self.handles.len() - 1
+            }
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<&Handle> {
+        self.handles.get(fd)?.as_ref()
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Option<Handle> {
+        self.handles.get_mut(fd)?.take()
+    }
+
+    /// The number of open handles
+    pub fn count(&self) -> usize {
+        self.handles.iter().filter(|h| h.is_some()).count()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Exited,
+}
+
+#[derive(Debug)]
+pub struct Process {
+    id: Pid,
+    name: String,
+    parent: Option<Pid>,
+    children: Vec<Pid>,
+    /// The ids of the threads that haven't exited
+    threads: Vec<usize>,
+    /// None once the process has exited
+    space: Option<AddressSpace>,
+    handles: HandleTable,
+    credentials: Credentials,
+    state: State,
+    exit_status: i32,
+    /// The signal the process was killed by. Its threads exit on their
+    /// way back to user mode, and the status can't be changed any more.
+    killed: Option<i32>,
+}
+
+impl Process {
+    pub fn id(&self) -> Pid {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[Pid] {
+        &self.children
+    }
+
+    pub fn threads(&self) -> &[usize] {
+        &self.threads
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        self.credentials
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn exit_status(&self) -> i32 {
+        self.exit_status
+    }
+
+    pub fn killed(&self) -> Option<i32> {
+        self.killed
+    }
+
+    pub fn handles(&self) -> &HandleTable {
+        &self.handles
+    }
+
+    pub fn handles_mut(&mut self) -> &mut HandleTable {
+        &mut self.handles
+    }
+
+    /// The root of the process's page table, until it exits
+    pub fn page_table(&self) -> Option<PhysicalPage> {
+        self.space.as_ref().map(AddressSpace::root)
+    }
+
+    /// Called by thread::spawn_in
+    pub(crate) fn add_thread(&mut self, thread: usize) {
+        self.threads.push(thread);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    NoSuchProcess,
+    NotChild,
+    Load(ElfError),
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(error: ElfError) -> Self {
+        ProcessError::Load(error)
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessError::NoSuchProcess => f.write_str("no such process"),
+            ProcessError::NotChild => f.write_str("not a child process"),
+            ProcessError::Load(error) => write!(f, "can't load: {}", error),
+        }
+    }
+}
+
+lazy_static! {
+    /// Every process that hasn't been reaped
+    static ref PROCESSES: RwLock<BTreeMap<Pid, ProcessArc>> =
+        RwLock::new(BTreeMap::new());
+}
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+/// Woken whenever a process exits. EXITS counts the exits, so a waiter can
+/// tell whether one happened between looking and blocking.
+static EXITED: WaitQueue = WaitQueue::new();
+static EXITS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn get(pid: Pid) -> Option<ProcessArc> {
+    PROCESSES.read().get(&pid).cloned()
+}
+
+/// The process of the running thread, or None for a kernel thread
+pub fn current() -> Option<ProcessArc> {
+    thread::process()
+}
+
+/// Make a process with no threads yet, as a child of the running process.
+/// It inherits its parent's credentials and handles; processes created by
+/// the kernel run as root with the console open.
+pub fn create(name: &str, space: AddressSpace) -> ProcessArc {
+    let parent = current();
+    let (parent_id, credentials, handles) = match &parent {
+        Some(parent) => {
+            let parent = parent.read();
+            (Some(parent.id), parent.credentials, parent.handles.clone())
+        }
+        None => (None, Credentials::ROOT, HandleTable::with_console()),
+    };
+    let id = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let process = Arc::new(RwLock::new(Process {
+        id,
+        name: name.to_string(),
+        parent: parent_id,
+        children: Vec::new(),
+        threads: Vec::new(),
+        space: Some(space),
+        handles,
+        credentials,
+        state: State::Running,
+        exit_status: 0,
+        killed: None,
+    }));
+    if let Some(parent) = &parent {
+        parent.write().children.push(id);
+    }
+    PROCESSES.write().insert(id, process.clone());
+    process
+}
+
+/// Load the executable `image` into a new process, and start a thread at
+/// its entry point with `argv` on the stack.
+pub fn spawn(
+    name: &str,
+    image: &[u8],
+    argv: &[&str],
+) -> Result<ProcessArc, ProcessError> {
+    let program = elf::load(image, argv, &[])?;
+    let (entry, stack_pointer) = (program.entry, program.stack_pointer);
+    let process = create(name, program.space);
+    thread::spawn_in(&process, move || {
+        x86::jmp_to_user(entry.0, stack_pointer.0);
+    });
+    Ok(process)
+}
+
+/// Exit the running thread, setting the process's exit status to
+/// `status`. Only the calling thread ends: the process exits with the last
+/// status set once all of its threads have exited, or with the signal
+/// status if it was killed.
+pub fn exit_thread(status: i32) -> ! {
+    if let Some(process) = current() {
+        let mut p = process.write();
+        if p.killed.is_none() {
+            p.exit_status = status;
+        }
+    }
+    thread::exit();
+}
+
+/// Kill `process` with `signal`. Each of its threads exits the next time
+/// it would return to user mode, and the process exits with status
+/// 128 + `signal`. Killing it again changes nothing.
+pub fn kill(process: &ProcessArc, signal: i32) {
+    let mut p = process.write();
+    if p.killed.is_none() {
+        p.killed = Some(signal);
+        p.exit_status = 128 + signal;
+    }
+}
+
+/// Exit the running thread if its process was killed. For the way back to
+/// user mode, which interrupted no kernel code, so interrupts can be
+/// turned on for the exit.
+pub fn exit_if_killed() {
+    let killed = current().and_then(|process| process.read().killed);
+    if killed.is_some() {
+        x86::enable_irqs();
+        thread::exit();
+    }
+}
+
+/// Remove an exited process from the process table and from its parent's
+/// children. Doing it twice is harmless.
+fn reap(pid: Pid, parent: Option<Pid>) {
+    PROCESSES.write().remove(&pid);
+    if let Some(parent) = parent.and_then(get) {
+        parent.write().children.retain(|&child| child != pid);
+    }
+}
+
+/// Called by thread::exit, after the exiting thread stopped switching to
+/// the process's page table. The last thread out frees the process's
+/// resources, and reaps the process if it has no parent to do it.
+pub fn thread_exited(process: &ProcessArc, thread: usize) {
+    let (pid, parent, space, handles, children) = {
+        let mut p = process.write();
+        p.threads.retain(|&t| t != thread);
+        if !p.threads.is_empty() {
+            return;
+        }
+        p.state = State::Exited;
+        (
+            p.id,
+            p.parent,
+            p.space.take(),
+            mem::take(&mut p.handles),
+            mem::take(&mut p.children),
+        )
+    };
+    // The address space is probably active, and dropping it switches to
+    // the kernel's page table
+    drop(space);
+    drop(handles);
+    // The parent and state are checked under the child's lock, which the
+    // child also holds as it exits, so an orphan is reaped exactly once
+    // whichever of the two exits first.
+    for child in children.into_iter().filter_map(get) {
+        let exited = {
+            let mut c = child.write();
+            c.parent = None;
+            c.state == State::Exited
+        };
+        if exited {
+            reap(child.read().id, None);
+        }
+    }
+    if parent.is_none() {
+        info!(
+            "process {} exited with status {}",
+            pid,
+            process.read().exit_status
+        );
+        reap(pid, None);
+    }
+    EXITS.fetch_add(1, Ordering::SeqCst);
+    EXITED.wake_all();
+}
+
+/// Reap `process` if it has exited and `reaper` may reap it, returning its
+/// exit status. Ok(None) if it hasn't exited yet.
+fn try_reap(
+    process: &ProcessArc,
+    reaper: Option<Pid>,
+) -> Result<Option<i32>, ProcessError> {
+    let (pid, parent, state, status) = {
+        let p = process.read();
+        (p.id, p.parent, p.state, p.exit_status)
+    };
+    if parent.is_some() && parent != reaper {
+        return Err(ProcessError::NotChild);
+    }
+    if state != State::Exited {
+        return Ok(None);
+    }
+    reap(pid, parent);
+    Ok(Some(status))
+}
+
+/// Wait for process `pid` to exit, reap it if it hasn't been already, and
+/// return its exit status. It must be a child of the running process, or
+/// have no parent if this is a kernel thread. Processes with no parent are
+/// reaped as they exit, but the status is still there for a waiter that
+/// found the process before then.
+pub fn wait(pid: Pid) -> Result<i32, ProcessError> {
+    let reaper = current().map(|process| process.read().id);
+    let process = get(pid).ok_or(ProcessError::NoSuchProcess)?;
+    loop {
+        let exits = EXITS.load(Ordering::SeqCst);
+        if let Some(status) = try_reap(&process, reaper)? {
+            return Ok(status);
+        }
+        x86::without_interrupts(|| {
+            if EXITS.load(Ordering::SeqCst) == exits {
+                EXITED.wait();
+            }
+        });
+    }
+}
+
+pub fn register_commands() {
+    shell::register(Command {
+        name: "exec",
+        usage: "exec <program> [args...]",
+        help: "start a program from the boot modules in a new process",
+        run: |args| {
+            let name = args.first().ok_or("missing program")?;
+            let image = elf::find_program(name).ok_or("no such program")?;
+            match spawn(name, image, args) {
+                Ok(process) => {
+                    println!("started process {}", process.read().id());
+                    Ok(())
+                }
+                Err(error) => {
+                    println!("{}: {}", name, error);
+                    Err("can't start program")
+                }
+            }
+        },
+    });
+    shell::register(Command {
+        name: "ps",
+        usage: "ps",
+        help: "list processes",
+        run: |_| {
+            let processes: Vec<ProcessArc> =
+                PROCESSES.read().values().cloned().collect();
+            println!(
+                "{:>6} {:>6} {:<8} {:>5} {:>7} {:>7} {}",
+                "pid", "ppid", "state", "uid", "threads", "handles", "name"
+            );
+            for process in processes {
+                let p = process.read();
+                let parent = match p.parent {
+                    Some(parent) => parent.to_string(),
+                    None => String::from("-"),
+                };
+                println!(
+                    "{:>6} {:>6} {:<8} {:>5} {:>7} {:>7} {}",
+                    p.id,
+                    parent,
+                    format!("{:?}", p.state),
+                    p.credentials.uid,
+                    p.threads.len(),
+                    p.handles.count(),
+                    p.name
+                );
+            }
+            Ok(())
+        },
+    });
+    shell::register(Command {
+        name: "wait",
+        usage: "wait <pid>",
+        help: "wait for a process to exit and reap it",
+        run: |args| {
+            let pid = shell::parse_number(args.first().ok_or("missing pid")?)?;
+            match wait(pid) {
+                Ok(status) => {
+                    println!("process {} exited with status {}", pid, status);
+                    Ok(())
+                }
+                Err(ProcessError::NoSuchProcess) => Err("no such process"),
+                Err(_) => Err("process has a parent to reap it"),
+            }
+        },
+    });
+}
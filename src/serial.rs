@@ -1,11 +1,18 @@
-use core::fmt::{self, Write};
-use alloc::collections::VecDeque;
+use crate::interrupt;
 use crate::sync::Mutex;
-use crate::x86::{inb, outb};
+use crate::thread::WaitQueue;
+use crate::tty::CharDevice;
+use crate::x86::{self, inb, outb};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct SerialPort {
     port: u16,
     buffer: VecDeque<u8>,
+    tx_buffer: VecDeque<u8>,
+    interrupt_tx: bool,
 }
 
 const UART_DATA: u16 = 0;
@@ -18,11 +25,69 @@ const UART_MODEM_CTRL: u16 = 4;
 const UART_LINE_STATUS: u16 = 5;
 const UART_MODEM_STATUS: u16 = 6;
 
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+const LINE_STATUS_THR_EMPTY: u8 = 0x20;
+
+const INTERRUPT_RX: u8 = 0x01;
+const INTERRUPT_THR_EMPTY: u8 = 0x02;
+
+/// The 16550 transmit FIFO takes this many bytes once THR is empty
+const TX_FIFO_SIZE: usize = 16;
+
+/// Bytes received beyond this are dropped until someone reads
+const RX_BUFFER_SIZE: usize = 4096;
+
+/// Output waiting for the UART. If this fills up, the writer sends the
+/// oldest bytes itself with polling to make room.
+const TX_BUFFER_SIZE: usize = 4096;
+
+/// Send one byte, waiting for the transmitter to have room first.
+fn write_polled(port: u16, byte: u8) {
+    unsafe {
+        while inb(port + UART_LINE_STATUS) & LINE_STATUS_THR_EMPTY == 0 {}
+        outb(port + UART_DATA, byte);
+    }
+}
+
+/// Receive one byte, waiting for it to arrive.
+fn read_polled(port: u16) -> u8 {
+    unsafe {
+        while inb(port + UART_LINE_STATUS) & LINE_STATUS_DATA_READY == 0 {}
+        inb(port + UART_DATA)
+    }
+}
+
+/// Writes straight to a UART, waiting for room before every byte. This
+/// needs no lock, allocation or interrupts, so it works during early boot
+/// and in the panic handler.
+pub struct PolledWriter {
+    port: u16,
+}
+
+impl PolledWriter {
+    pub const fn new(port: u16) -> Self {
+        Self { port }
+    }
+}
+
+impl fmt::Write for PolledWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            write_polled(self.port, byte);
+        }
+        Ok(())
+    }
+}
+
 impl SerialPort {
     pub fn new(port: u16) -> Self {
+        // Both buffers are allocated up front and never grow, so the
+        // interrupt handler doesn't have to allocate.
         SerialPort {
             port,
-            buffer: VecDeque::new(),
+            buffer: VecDeque::with_capacity(RX_BUFFER_SIZE),
+            tx_buffer: VecDeque::with_capacity(TX_BUFFER_SIZE),
+            interrupt_tx: false,
         }
     }
 
@@ -36,7 +101,74 @@ impl SerialPort {
             outb(self.port + UART_FIFO_CTRL, 0xC7);
             outb(self.port + UART_MODEM_CTRL, 0x0B);
 
-            outb(self.port + UART_INTERRUPT, 0x09);
+            outb(self.port + UART_INTERRUPT, INTERRUPT_RX);
+        }
+    }
+
+    /// Start buffering output and sending it from the THR empty interrupt.
+    /// Until this is called, writes are polled.
+    pub fn use_interrupt_tx(&mut self) {
+        self.interrupt_tx = true;
+        self.start_tx();
+    }
+
+    fn set_tx_interrupt(&mut self, enabled: bool) {
+        let mut interrupts = INTERRUPT_RX;
+        if enabled {
+            interrupts |= INTERRUPT_THR_EMPTY;
+        }
+        unsafe { outb(self.port + UART_INTERRUPT, interrupts) };
+    }
+
+    /// Refill the transmit FIFO from the buffer if it is empty.
+    fn fill_fifo(&mut self) {
+        if self.status() & LINE_STATUS_THR_EMPTY == 0 {
+            return;
+        }
+        for _ in 0..TX_FIFO_SIZE {
+            match self.tx_buffer.pop_front() {
+                Some(byte) => unsafe { outb(self.port + UART_DATA, byte) },
+                None => break,
+            }
+        }
+    }
+
+    /// Send what can be sent now, and ask for an interrupt when there is
+    /// room for more if anything is left.
+    fn start_tx(&mut self) {
+        self.fill_fifo();
+        let pending = !self.tx_buffer.is_empty();
+        self.set_tx_interrupt(pending);
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if !self.interrupt_tx {
+            write_polled(self.port, byte);
+            return;
+        }
+        if self.tx_buffer.len() >= TX_BUFFER_SIZE {
+            let oldest = self.tx_buffer.pop_front().unwrap();
+            write_polled(self.port, oldest);
+        }
+        self.tx_buffer.push_back(byte);
+    }
+
+    /// Send everything still in the transmit buffer by polling.
+    pub fn flush_polled(&mut self) {
+        while let Some(byte) = self.tx_buffer.pop_front() {
+            write_polled(self.port, byte);
+        }
+    }
+
+    /// Check there is a UART here by sending a byte through it in loopback
+    /// mode.
+    fn probe(&mut self) -> bool {
+        unsafe {
+            outb(self.port + UART_MODEM_CTRL, 0x1E); // loopback
+            outb(self.port + UART_DATA, 0xAE);
+            let present = inb(self.port + UART_DATA) == 0xAE;
+            outb(self.port + UART_MODEM_CTRL, 0x0B);
+            present
         }
     }
 
@@ -45,34 +177,294 @@ impl SerialPort {
     }
 
     fn data_available(&self) -> bool {
-        self.status() & 0x01 == 0
+        self.status() & LINE_STATUS_DATA_READY != 0
     }
 
+    /// Take a byte straight from the UART, if one has arrived.
+    fn receive(&mut self) -> Option<u8> {
+        if self.data_available() {
+            Some(unsafe { inb(self.port + UART_DATA) })
+        } else {
+            None
+        }
+    }
+
+    /// Move everything the UART has received into the buffer, and send
+    /// more output if there is room.
     pub unsafe fn handle_irq(&mut self) {
-        let byte = inb(self.port + UART_DATA);
-        self.buffer.push_back(byte);
+        while self.data_available() {
+            let byte = inb(self.port + UART_DATA);
+            if self.buffer.len() < RX_BUFFER_SIZE {
+                self.buffer.push_back(byte);
+            }
+        }
+        if self.interrupt_tx {
+            self.start_tx();
+        }
+    }
+}
+
+impl SerialPort {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+        if self.interrupt_tx {
+            self.start_tx();
+        }
     }
 }
 
 impl fmt::Write for SerialPort {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            unsafe { outb(self.port, c as u8) };
-        }
+        self.write_bytes(s.as_bytes());
         Ok(())
     }
 }
 
+/// A UART along with the threads waiting to read from it.
+pub struct SerialDevice {
+    name: &'static str,
+    irq: usize,
+    /// The I/O base, for polling without the lock
+    base: u16,
+    port: Mutex<SerialPort>,
+    rx_waiters: WaitQueue,
+}
+
+impl SerialDevice {
+    fn new(name: &'static str, irq: usize, port: SerialPort) -> Self {
+        Self {
+            name,
+            irq,
+            base: port.port,
+            port: Mutex::new(port),
+            rx_waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn irq(&self) -> usize {
+        self.irq
+    }
+
+    /// The interrupt handler also takes the port lock, so it has to be
+    /// locked with interrupts disabled.
+    fn with_port<T>(&self, f: impl FnOnce(&mut SerialPort) -> T) -> T {
+        x86::without_interrupts(|| f(&mut self.port.lock()))
+    }
+
+    pub fn write_fmt(&self, args: fmt::Arguments) {
+        self.with_port(|port| port.write_fmt(args).unwrap());
+    }
+
+    pub fn write_str(&self, s: &str) {
+        self.with_port(|port| port.write_str(s).unwrap());
+    }
+
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        self.with_port(|port| port.write_bytes(bytes));
+    }
+
+    /// Switch to buffered, interrupt-driven output. The device's IRQ has
+    /// to be unmasked first.
+    pub fn use_interrupt_tx(&self) {
+        self.with_port(|port| port.use_interrupt_tx());
+    }
+
+    fn handle_irq(&self) {
+        self.with_port(|port| unsafe { port.handle_irq() });
+        self.rx_waiters.wake_all();
+    }
+
+    /// Take a byte from the receive buffer, if there is one.
+    pub fn try_read_byte(&self) -> Option<u8> {
+        self.with_port(|port| port.buffer.pop_front())
+    }
+
+    /// Read into `buf` whatever has been received, without waiting.
+    /// Returns the number of bytes read.
+    pub fn try_read(&self, buf: &mut [u8]) -> usize {
+        self.with_port(|port| {
+            let count = buf.len().min(port.buffer.len());
+            for (out, byte) in buf.iter_mut().zip(port.buffer.drain(..count)) {
+                *out = byte;
+            }
+            count
+        })
+    }
+
+    /// Read into `buf`, blocking the calling thread until at least one
+    /// byte has arrived. Returns the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        x86::without_interrupts(|| loop {
+            let count = self.try_read(buf);
+            if count > 0 {
+                return count;
+            }
+            self.rx_waiters.wait();
+        })
+    }
+
+    /// Read one byte, blocking the calling thread until it arrives.
+    pub fn read_byte(&self) -> u8 {
+        let mut byte = [0u8];
+        self.read(&mut byte);
+        byte[0]
+    }
+
+    /// Read one byte by polling the UART. For code that can't block or
+    /// rely on interrupts, like the debugger stub. That may have stopped
+    /// the kernel while it held the port lock, so the UART is used
+    /// directly, and bytes the interrupt handler already received are only
+    /// taken if the lock is free.
+    pub fn read_byte_polled(&self) -> u8 {
+        let buffered = x86::without_interrupts(|| {
+            self.port
+                .try_lock()
+                .and_then(|mut port| port.buffer.pop_front())
+        });
+        buffered.unwrap_or_else(|| read_polled(self.base))
+    }
+
+    /// Send `bytes` by polling, without the port lock.
+    pub fn write_polled(&self, bytes: &[u8]) {
+        for &byte in bytes {
+            write_polled(self.base, byte);
+        }
+    }
+}
+
+impl CharDevice for SerialDevice {
+    fn read_byte(&self) -> u8 {
+        SerialDevice::read_byte(self)
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        self.write_bytes(bytes);
+    }
+}
+
+/// The standard PC serial ports: name, I/O base and IRQ line.
+const COM_PORTS: [(&str, u16, usize); 4] = [
+    ("com1", 0x3f8, 4),
+    ("com2", 0x2f8, 3),
+    ("com3", 0x3e8, 4),
+    ("com4", 0x2e8, 3),
+];
+
+/// Index into DEVICES of the machine-readable channel, if there is one
+const NO_CHANNEL: usize = usize::MAX;
+static MACHINE_CHANNEL: AtomicUsize = AtomicUsize::new(NO_CHANNEL);
+
 lazy_static! {
-    pub static ref GLOBAL_SERIAL: Mutex<SerialPort> = {
-        let mut serial = SerialPort::new(0x3f8);
-        serial.init();
-        Mutex::new(serial)
+    /// Every serial port found. COM1 is the console and is always first,
+    /// even if the probe doesn't find it, so there is somewhere to print.
+    static ref DEVICES: Vec<SerialDevice> = {
+        let mut devices = Vec::new();
+        for (i, &(name, base, irq)) in COM_PORTS.iter().enumerate() {
+            let mut port = SerialPort::new(base);
+            port.init();
+            if i == 0 || port.probe() {
+                devices.push(SerialDevice::new(name, irq, port));
+            }
+        }
+        devices
     };
 }
 
+pub fn devices() -> &'static [SerialDevice] {
+    &DEVICES
+}
+
+pub fn device(name: &str) -> Option<&'static SerialDevice> {
+    DEVICES.iter().find(|device| device.name == name)
+}
+
+/// The human console, where print! goes.
+pub fn console() -> &'static SerialDevice {
+    &DEVICES[0]
+}
+
+/// Send the machine-readable channel (logs, traces, test results) to the
+/// port called `name`. Returns false if there is no such port.
+pub fn route_machine_channel(name: &str) -> bool {
+    match DEVICES.iter().position(|device| device.name == name) {
+        Some(index) => {
+            MACHINE_CHANNEL.store(index, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn machine_channel() -> Option<&'static SerialDevice> {
+    DEVICES.get(MACHINE_CHANNEL.load(Ordering::Relaxed))
+}
+
 pub fn serial_print(args: fmt::Arguments) {
-    GLOBAL_SERIAL.lock().write_fmt(args).unwrap();
+    console().write_fmt(args);
+}
+
+/// Write to the machine-readable channel. Output is dropped if no port is
+/// routed there.
+pub fn machine_print(args: fmt::Arguments) {
+    if let Some(device) = machine_channel() {
+        device.write_fmt(args);
+    }
+}
+
+/// Switch every port to buffered, interrupt-driven output. Their IRQs have
+/// to be unmasked first.
+pub fn use_interrupt_tx() {
+    for device in devices() {
+        device.use_interrupt_tx();
+    }
+}
+
+/// Push out any buffered console output by polling, for the panic handler.
+/// If the console is locked, whoever holds it was interrupted by the panic
+/// and the buffer is left alone.
+pub fn emergency_flush() {
+    if let Some(mut port) = console().port.try_lock() {
+        port.flush_polled();
+    }
+}
+
+/// Hook every port's IRQ. COM1 and COM3 share IRQ 4, and COM2 and COM4
+/// share IRQ 3, so each port has its own handler chained on the line.
+pub fn register_irqs() {
+    for (index, device) in devices().iter().enumerate() {
+        interrupt::register_irq(
+            device.irq,
+            device.name,
+            |_, index| devices()[index].handle_irq(),
+            index,
+        )
+        .expect("failed to register serial interrupt");
+    }
+}
+
+pub fn try_read_byte() -> Option<u8> {
+    console().try_read_byte()
+}
+
+pub fn try_read(buf: &mut [u8]) -> usize {
+    console().try_read(buf)
+}
+
+pub fn read(buf: &mut [u8]) -> usize {
+    console().read(buf)
+}
+
+pub fn read_byte() -> u8 {
+    console().read_byte()
 }
 
 #[macro_export]
@@ -88,3 +480,17 @@ macro_rules! println {
     ($fmt:expr) => ($crate::print!(concat!($fmt, "\r\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\r\n"), $($arg)*));
 }
+
+#[macro_export]
+macro_rules! mprint {
+    ($($arg:tt)*) => {
+        $crate::serial::machine_print(format_args!($($arg)*));
+    };
+}
+
+#[macro_export]
+macro_rules! mprintln {
+    () => ($crate::mprint!("\n"));
+    ($fmt:expr) => ($crate::mprint!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::mprint!(concat!($fmt, "\n"), $($arg)*));
+}
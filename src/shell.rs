@@ -0,0 +1,106 @@
+//! A command shell on the serial console, for poking at a running system.
+//! Subsystems add their own commands with `register`.
+
+use crate::sync::Mutex;
+use crate::tty::{self, TtyError};
+use crate::{thread, x86};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub type CommandResult = Result<(), &'static str>;
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: fn(args: &[&str]) -> CommandResult,
+}
+
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+const PROMPT: &str = "> ";
+
+/// Add a command, replacing any command with the same name.
+pub fn register(command: Command) {
+    let mut commands = COMMANDS.lock();
+    commands.retain(|c| c.name != command.name);
+    commands.push(command);
+    commands.sort_by_key(|c| c.name);
+}
+
+fn find(name: &str) -> Option<Command> {
+    COMMANDS.lock().iter().find(|c| c.name == name).copied()
+}
+
+/// Register the shell's own commands and start the shell thread.
+pub fn init() {
+    register(Command {
+        name: "help",
+        usage: "help",
+        help: "list commands",
+        run: help,
+    });
+    register(Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "reset the machine",
+        run: |_| x86::reboot(),
+    });
+
+    thread::spawn(run);
+}
+
+fn run() {
+    let mut line = String::new();
+    loop {
+        print!("{}", PROMPT);
+        line.clear();
+        match tty::console().read_line(&mut line) {
+            Ok(0) => {
+                println!();
+            }
+            Ok(_) => execute(&line),
+            // There's nothing to interrupt or suspend, so just prompt
+            // again.
+            Err(TtyError::Interrupted) | Err(TtyError::Suspended) => {}
+        }
+    }
+}
+
+fn execute(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let name = match args.first() {
+        Some(name) => *name,
+        None => return,
+    };
+    let command = match find(name) {
+        Some(command) => command,
+        None => {
+            println!("{}: command not found, try help", name);
+            return;
+        }
+    };
+    if let Err(error) = (command.run)(&args[1..]) {
+        println!("{}: {}", name, error);
+        println!("usage: {}", command.usage);
+    }
+}
+
+fn help(_args: &[&str]) -> CommandResult {
+    let commands = COMMANDS.lock().clone();
+    for command in commands {
+        println!("{:<24} {}", command.usage, command.help);
+    }
+    Ok(())
+}
+
+/// Parse a number given to a command, in hex if it starts with 0x.
+pub fn parse_number(s: &str) -> Result<usize, &'static str> {
+    let result = if let Some(hex) = s.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    result.map_err(|_| "bad number")
+}
@@ -0,0 +1,138 @@
+use crate::memory::{
+    PageFlags, PageTable, PagingError, PhysicalPage, VirtualAddress, PAGE_SIZE,
+};
+use crate::phy_map;
+use crate::util::round_up;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// The pages backing a shared memory object. Each page holds one phy_map
+/// reference for the object itself and one more for every mapping of it,
+/// so the pages go back to the allocator once the last handle and the last
+/// mapping are gone.
+#[derive(Debug)]
+struct Object {
+    pages: Vec<PhysicalPage>,
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        for page in &self.pages {
+            phy_map::free(page.base_address());
+        }
+    }
+}
+
+/// A handle to a shared memory object. Handles are cheap to clone, and each
+/// one keeps the memory alive.
+#[derive(Clone, Debug)]
+pub struct SharedMemory(Arc<Object>);
+
+/// One mapping of a shared memory object into a page table. The pages are
+/// unmapped when this is dropped, so it must be dropped before the page
+/// table is freed. Mappings into a user address space belong to the
+/// AddressSpace, which does that; see AddressSpace::map_shared.
+#[derive(Debug)]
+pub struct SharedMapping {
+    object: Arc<Object>,
+    table: PhysicalPage,
+    base: VirtualAddress,
+    flags: PageFlags,
+}
+
+impl SharedMemory {
+    /// Create a zero-filled object of at least `size` bytes.
+    pub fn new(size: usize) -> Self {
+        let count = round_up(size, PAGE_SIZE) / PAGE_SIZE;
+        let pages = (0..count).map(|_| phy_map::alloc_zero().page()).collect();
+        SharedMemory(Arc::new(Object { pages }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.pages.len() * PAGE_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.pages.is_empty()
+    }
+
+    /// Map the whole object into `table` at `base` with the page flags in
+    /// `flags`. Nothing may already be mapped in that range.
+    pub fn map(
+        &self,
+        table: &mut PageTable,
+        base: VirtualAddress,
+        flags: PageFlags,
+    ) -> Result<SharedMapping, PagingError> {
+        if base.0 % PAGE_SIZE != 0 {
+            return Err(PagingError::Unaligned);
+        }
+
+        let range = (0..self.0.pages.len())
+            .map(|i| VirtualAddress(base.0 + i * PAGE_SIZE));
+        for v in range.clone() {
+            if !table.pte(v).is_nil() {
+                return Err(PagingError::AlreadyMapped);
+            }
+        }
+
+        for (i, (v, page)) in range.zip(self.0.pages.iter()).enumerate() {
+            if let Err(error) = table.map(v, *page, flags) {
+                // Undo the pages this call already mapped
+                for (j, page) in self.0.pages[..i].iter().enumerate() {
+                    let v = VirtualAddress(base.0 + j * PAGE_SIZE);
+                    if table.unmap(v).is_ok() {
+                        table.invalidate(v);
+                    }
+                    phy_map::free(page.base_address());
+                }
+                return Err(error);
+            }
+            phy_map::incref(page.base_address());
+        }
+
+        Ok(SharedMapping {
+            object: self.0.clone(),
+            table: table.0,
+            base,
+            flags,
+        })
+    }
+}
+
+impl SharedMapping {
+    pub fn base(&self) -> VirtualAddress {
+        self.base
+    }
+
+    pub fn len(&self) -> usize {
+        self.object.pages.len() * PAGE_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.object.pages.is_empty()
+    }
+
+    pub fn flags(&self) -> PageFlags {
+        self.flags
+    }
+
+    /// A new handle to the object this is a mapping of.
+    pub fn object(&self) -> SharedMemory {
+        SharedMemory(self.object.clone())
+    }
+}
+
+impl Drop for SharedMapping {
+    fn drop(&mut self) {
+        let mut table = PageTable(self.table);
+
+        for (i, page) in self.object.pages.iter().enumerate() {
+            let v = VirtualAddress(self.base.0 + i * PAGE_SIZE);
+            if table.unmap(v).is_ok() {
+                table.invalidate(v);
+            }
+            phy_map::free(page.base_address());
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::block::{BlockDevice, BlockError};
+use crate::memory::{
+    lower_half_end, PageFlags, PageTable, PageTableEntry, PhysicalAddress,
+    VirtualAddress, PAGE_SIZE,
+};
+use crate::sync::Mutex;
+use crate::util::round_down;
+use crate::{phy_map, x86};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Reclaim walks the address space like a clock hand: a page that has been
+/// accessed since the last pass gets its accessed bit cleared and another
+/// chance, a page that hasn't is written out. This is enough passes for
+/// every page to be looked at twice.
+const RECLAIM_PASSES: usize = 3;
+
+struct SwapArea {
+    device: Box<dyn BlockDevice>,
+    used: Vec<bool>,
+    hand: usize,
+}
+
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+
+impl SwapArea {
+    fn blocks_per_slot(&self) -> usize {
+        PAGE_SIZE / self.device.block_size()
+    }
+
+    fn alloc_slot(&mut self) -> Option<usize> {
+        let slot = self.used.iter().position(|used| !used)?;
+        self.used[slot] = true;
+        Some(slot)
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        self.used[slot] = false;
+    }
+
+    fn page_bytes(page: PhysicalAddress) -> &'static mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                page.direct_map() as *mut u8,
+                PAGE_SIZE,
+            )
+        }
+    }
+
+    fn write_slot(
+        &mut self,
+        slot: usize,
+        page: PhysicalAddress,
+    ) -> Result<(), BlockError> {
+        let block = slot * self.blocks_per_slot();
+        self.device.write(block, Self::page_bytes(page))
+    }
+
+    fn read_slot(
+        &mut self,
+        slot: usize,
+        page: PhysicalAddress,
+    ) -> Result<(), BlockError> {
+        let block = slot * self.blocks_per_slot();
+        self.device.read(block, Self::page_bytes(page))
+    }
+
+    /// Look at one page for reclaim. Returns whether it was swapped out.
+    fn consider(
+        &mut self,
+        v: VirtualAddress,
+        pte: &mut PageTableEntry,
+    ) -> bool {
+        let flags = pte.flags();
+        let page = pte.deref();
+
+        if !flags.contains(PageFlags::USERMODE) {
+            return false;
+        }
+        // Pages with more than one reference are shared with another
+        // mapping, only private anonymous memory is swapped.
+        if phy_map::refcount(page.base_address()) != Some(1) {
+            return false;
+        }
+        if flags.contains(PageFlags::ACCESSED) {
+            *pte = PageTableEntry::from_page_flags(
+                page,
+                flags - PageFlags::ACCESSED,
+            );
+            x86::invlpg(v.0);
+            return false;
+        }
+
+        let slot = match self.alloc_slot() {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        *pte = PageTableEntry::swapped(slot, flags);
+        x86::invlpg(v.0);
+
+        if let Err(e) = self.write_slot(slot, page.base_address()) {
+            error!("failed to write slot {}: {:?}", slot, e);
+            self.free_slot(slot);
+            *pte = PageTableEntry::from_page_flags(page, flags);
+            return false;
+        }
+
+        phy_map::free(page.base_address());
+        true
+    }
+}
+
+/// Use `device` as swap space, replacing any swap set up before.
+pub fn init(device: Box<dyn BlockDevice>) {
+    let slots = device.len() / PAGE_SIZE;
+    info!("{} slots", slots);
+    *SWAP.lock() = Some(SwapArea {
+        device,
+        used: vec![false; slots],
+        hand: 0,
+    });
+}
+
+/// Swap slots (used, total), or None if there is no swap.
+pub fn usage() -> Option<(usize, usize)> {
+    let swap = SWAP.lock();
+    let area = swap.as_ref()?;
+    let used = area.used.iter().filter(|used| **used).count();
+    Some((used, area.used.len()))
+}
+
+/// Try to free up to `count` pages by writing cold user pages in the
+/// current address space out to swap. Returns the number of pages freed.
+pub fn reclaim(count: usize) -> usize {
+    let mut swap = match SWAP.try_lock() {
+        Some(guard) => guard,
+        None => return 0,
+    };
+    let area = match swap.as_mut() {
+        Some(area) => area,
+        None => return 0,
+    };
+
+    let mut table = PageTable::current();
+    let mut freed = 0;
+
+    for _ in 0..RECLAIM_PASSES {
+        table.for_each_page_mut(area.hand..lower_half_end(), |v, pte| {
+            if area.consider(v, pte) {
+                freed += 1;
+            }
+            area.hand = v.0 + PAGE_SIZE;
+            freed < count
+        });
+        if freed >= count {
+            break;
+        }
+        area.hand = 0;
+    }
+
+    freed
+}
+
+/// Give back the slot of a swapped out page that is being thrown away.
+pub fn discard(slot: usize) {
+    if let Some(area) = SWAP.lock().as_mut() {
+        area.free_slot(slot);
+    }
+}
+
+/// Bring the page containing `v` back in from swap. Returns false if it
+/// isn't swapped out, in which case the fault is a real one.
+pub fn handle_fault(v: VirtualAddress) -> bool {
+    let mut table = PageTable::current();
+    let v = VirtualAddress(round_down(v.0, PAGE_SIZE));
+
+    let slot = match table.pte(v).swap_slot() {
+        Some(slot) => slot,
+        None => return false,
+    };
+
+    // Allocate before taking the swap lock, since the allocator may need
+    // to reclaim to find the page.
+    let page = match phy_map::try_alloc() {
+        Some(page) => page,
+        None => return false,
+    };
+
+    let mut swap = SWAP.lock();
+    let area = swap.as_mut().expect("swapped page with no swap area");
+    if let Err(e) = area.read_slot(slot, page) {
+        panic!("swap: failed to read slot {}: {:?}", slot, e);
+    }
+    area.free_slot(slot);
+
+    let pte = table.pte_mut(v).expect("swapped page has no page table");
+    let flags = (pte.flags() - PageFlags::SWAPPED) | PageFlags::PRESENT;
+    *pte = PageTableEntry::from_page_flags(page.page(), flags);
+    true
+}
@@ -0,0 +1,237 @@
+//! System calls. User mode can enter with SYSCALL or with int 0x80; both
+//! arrive here with an InterruptFrame. The number is in rax and up to six
+//! arguments in rdi, rsi, rdx, r10, r8 and r9, as on Linux, since SYSCALL
+//! uses rcx. The result goes back in rax, with errors as -errno.
+
+use crate::memory::VirtualAddress;
+use crate::process::{self, Handle};
+use crate::time::{self, Duration};
+use crate::tty::{self, TtyError};
+use crate::user::{self, UserCopyError};
+use crate::x86::{self, InterruptFrame};
+use crate::{interrupt, thread};
+use core::fmt;
+
+pub const SYS_EXIT: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_READ: usize = 2;
+pub const SYS_YIELD: usize = 3;
+pub const SYS_SLEEP: usize = 4;
+pub const SYS_GETTID: usize = 5;
+pub const SYS_CLOCK: usize = 6;
+pub const SYS_GETPID: usize = 7;
+pub const SYS_CLOSE: usize = 8;
+
+/// How much of a read or write is copied through the kernel stack at once
+const CHUNK_SIZE: usize = 128;
+
+/// Errors returned to user mode, numbered as on Linux
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    Interrupted = 4,
+    BadFd = 9,
+    Fault = 14,
+    Invalid = 22,
+    NoSys = 38,
+}
+
+impl From<UserCopyError> for Errno {
+    fn from(_: UserCopyError) -> Self {
+        Errno::Fault
+    }
+}
+
+impl From<TtyError> for Errno {
+    fn from(_: TtyError) -> Self {
+        Errno::Interrupted
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Errno::Interrupted => "interrupted",
+            Errno::BadFd => "bad file descriptor",
+            Errno::Fault => "bad address",
+            Errno::Invalid => "invalid argument",
+            Errno::NoSys => "no such syscall",
+        };
+        f.write_str(s)
+    }
+}
+
+pub type SyscallResult = Result<usize, Errno>;
+
+/// The six argument registers, in order
+pub type Args = [usize; 6];
+
+type Handler = fn(args: &Args) -> SyscallResult;
+
+struct Syscall {
+    name: &'static str,
+    handler: Handler,
+}
+
+/// Indexed by syscall number
+static TABLE: [Syscall; 9] = [
+    Syscall {
+        name: "exit",
+        handler: sys_exit,
+    },
+    Syscall {
+        name: "write",
+        handler: sys_write,
+    },
+    Syscall {
+        name: "read",
+        handler: sys_read,
+    },
+    Syscall {
+        name: "yield",
+        handler: sys_yield,
+    },
+    Syscall {
+        name: "sleep",
+        handler: sys_sleep,
+    },
+    Syscall {
+        name: "gettid",
+        handler: sys_gettid,
+    },
+    Syscall {
+        name: "clock",
+        handler: sys_clock,
+    },
+    Syscall {
+        name: "getpid",
+        handler: sys_getpid,
+    },
+    Syscall {
+        name: "close",
+        handler: sys_close,
+    },
+];
+
+fn args(frame: &InterruptFrame) -> Args {
+    [frame.di, frame.si, frame.dx, frame.r10, frame.r8, frame.r9]
+}
+
+fn encode(result: SyscallResult) -> usize {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (errno as isize).wrapping_neg() as usize,
+    }
+}
+
+/// Run the syscall described by `frame` and put the result in its rax.
+pub fn dispatch(frame: &mut InterruptFrame) {
+    let number = frame.ax;
+    let args = args(frame);
+    let result = match TABLE.get(number) {
+        Some(syscall) => {
+            trace!("{}{:x?}", syscall.name, args);
+            (syscall.handler)(&args)
+        }
+        None => {
+            debug!("unknown syscall {}", number);
+            Err(Errno::NoSys)
+        }
+    };
+    frame.ax = encode(result);
+}
+
+/// Called by syscall_entry in syscall.asm
+#[no_mangle]
+pub unsafe extern "C" fn c_syscall_shim(frame: *mut InterruptFrame) {
+    dispatch(&mut *frame);
+    process::exit_if_killed();
+}
+
+/// Enable both ways into the kernel.
+pub fn init() {
+    x86::syscall_init();
+    interrupt::register(
+        x86::SYSCALL_VECTOR,
+        "syscall",
+        |frame, _| dispatch(frame),
+        0,
+    )
+    .expect("failed to register the syscall vector");
+}
+
+/// The handle `fd` of the calling process
+fn handle(fd: usize) -> Result<Handle, Errno> {
+    let process = process::current().ok_or(Errno::BadFd)?;
+    let handle = process.read().handles().get(fd).cloned();
+    handle.ok_or(Errno::BadFd)
+}
+
+/// exit(status). Only the calling thread exits; see process::exit_thread.
+fn sys_exit(args: &Args) -> SyscallResult {
+    process::exit_thread(args[0] as i32);
+}
+
+/// write(fd, buffer, length) -> bytes written
+fn sys_write(args: &Args) -> SyscallResult {
+    let (fd, buffer, len) = (args[0], args[1], args[2]);
+    match handle(fd)? {
+        Handle::Console => {}
+    }
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut done = 0;
+    while done < len {
+        let count = CHUNK_SIZE.min(len - done);
+        let src = VirtualAddress(buffer.wrapping_add(done));
+        user::copy_from_user(&mut chunk[..count], src)?;
+        tty::console().write(&chunk[..count]);
+        done += count;
+    }
+    Ok(done)
+}
+
+/// read(fd, buffer, length) -> bytes read, 0 at end of file. Like a read
+/// from the TTY, this returns at most one line.
+fn sys_read(args: &Args) -> SyscallResult {
+    let (fd, buffer, len) = (args[0], args[1], args[2]);
+    match handle(fd)? {
+        Handle::Console => {}
+    }
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let count = CHUNK_SIZE.min(len);
+    let count = tty::console().read(&mut chunk[..count])?;
+    user::copy_to_user(VirtualAddress(buffer), &chunk[..count])?;
+    Ok(count)
+}
+
+fn sys_yield(_args: &Args) -> SyscallResult {
+    thread::schedule();
+    Ok(0)
+}
+
+/// sleep(nanoseconds)
+fn sys_sleep(args: &Args) -> SyscallResult {
+    thread::sleep(Duration::from_nanos(args[0] as u64));
+    Ok(0)
+}
+
+fn sys_gettid(_args: &Args) -> SyscallResult {
+    Ok(thread::id())
+}
+
+/// clock() -> nanoseconds since boot
+fn sys_clock(_args: &Args) -> SyscallResult {
+    Ok(time::now().as_nanos() as usize)
+}
+
+fn sys_getpid(_args: &Args) -> SyscallResult {
+    let process = process::current().ok_or(Errno::Invalid)?;
+    let pid = process.read().id();
+    Ok(pid)
+}
+
+/// close(fd)
+fn sys_close(args: &Args) -> SyscallResult {
+    let process = process::current().ok_or(Errno::BadFd)?;
+    let handle = process.write().handles_mut().remove(args[0]);
+    handle.map(|_| 0).ok_or(Errno::BadFd)
+}
@@ -1,24 +1,62 @@
+use crate::memory::{self, PageTable, PhysicalPage, VirtualAddress, PAGE_SIZE};
+use crate::process::{self, ProcessArc};
+use crate::shell::{self, Command};
+use crate::time::{self, Duration};
 use crate::x86::{self, long_jump, set_jump, JmpBuf};
+use crate::{interrupt, timer};
+use alloc::alloc::Layout;
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::fmt;
 use core::mem;
 use core::ptr;
-use spin::RwLock;
-
-#[repr(C, align(32))]
-struct Stack([u8; Stack::SIZE]);
+use spin::{Mutex, RwLock};
+
+/// A thread's kernel stack, with an unmapped guard page below it so an
+/// overflow faults instead of corrupting the heap
+#[repr(C, align(4096))]
+struct Stack {
+    guard: [u8; PAGE_SIZE],
+    stack: [u8; Stack::SIZE],
+}
 
 impl Stack {
     const SIZE: usize = 4096;
 
     fn new_boxed() -> Box<Stack> {
-        Box::new(Stack([0; Self::SIZE]))
+        // Allocated in place, since it's bigger than the stack it would be
+        // built on
+        let layout = Layout::new::<Stack>();
+        let stack = unsafe { alloc::alloc::alloc_zeroed(layout) } as *mut Stack;
+        if stack.is_null() {
+            alloc::alloc::handle_alloc_error(layout);
+        }
+        let stack = unsafe { Box::from_raw(stack) };
+        memory::set_guard_page(stack.guard_page());
+        stack
+    }
+
+    fn guard_page(&self) -> VirtualAddress {
+        VirtualAddress(self.guard.as_ptr() as usize)
+    }
+
+    fn guard_contains(&self, addr: usize) -> bool {
+        let guard = self.guard_page().0;
+        (guard..guard + PAGE_SIZE).contains(&addr)
     }
 
     fn stack_ptr(&self) -> usize {
-        (&self.0[0] as *const u8).wrapping_add(Self::SIZE - 2048) as usize
+        (&self.stack[0] as *const u8).wrapping_add(Self::SIZE - 2048) as usize
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        memory::clear_guard_page(self.guard_page());
     }
 }
 
@@ -43,6 +81,11 @@ pub struct Thread {
     start_fn: Option<StartFn>,
     stack: Box<Stack>,
     state: State,
+    /// None for kernel threads
+    process: Option<ProcessArc>,
+    /// The page table to switch to when this thread runs. Kernel threads
+    /// run on whatever page table was active before them.
+    page_table: Option<PhysicalPage>,
 }
 
 impl Thread {
@@ -59,6 +102,8 @@ impl Thread {
             stack,
             context,
             state: State::Running,
+            process: None,
+            page_table: None,
         }
     }
 
@@ -73,6 +118,18 @@ impl Thread {
     fn is_running(&self) -> bool {
         self.state == State::Running
     }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn process(&self) -> Option<&ProcessArc> {
+        self.process.as_ref()
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -86,6 +143,11 @@ lazy_static! {
     static ref THREADS: RwLock<ThreadSet> = RwLock::new(ThreadSet::new());
 }
 
+/// Threads woken while THREADS was locked, for instance by an interrupt
+/// that arrived in the middle of spawn(). The scheduler moves them onto
+/// the run queue. Only locked with interrupts disabled.
+static WOKEN: Mutex<Vec<ThreadArc>> = Mutex::new(Vec::new());
+
 type ThreadArc = Arc<RwLock<Thread>>;
 
 #[derive(Debug)]
@@ -149,8 +211,37 @@ where
     THREADS.write().spawn(Box::new(func))
 }
 
+/// Start a thread in a user process, running in its address space.
+pub fn spawn_in<F>(process: &ProcessArc, func: F) -> ThreadArc
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let page_table = process.read().page_table();
+    // Nothing can be scheduled while THREADS is locked, so the thread is
+    // part of the process before it can run and exit.
+    let mut threads = THREADS.write();
+    let thread = threads.spawn(Box::new(func));
+    {
+        let mut th = thread.write();
+        th.process = Some(process.clone());
+        th.page_table = page_table;
+        process.write().add_thread(th.id);
+    }
+    thread
+}
+
 pub fn exit() -> ! {
     if let Some(thread) = running() {
+        let process = {
+            let mut th = thread.write();
+            // Stop switching to the address space before the process
+            // frees it
+            th.page_table = None;
+            th.process.take()
+        };
+        if let Some(process) = process {
+            process::thread_exited(&process, id());
+        }
         let mut th = thread.write();
         let id = th.id;
         th.state = State::Dead;
@@ -175,7 +266,7 @@ fn thread_entry() {
 }
 
 fn thread_idle() {
-    dprintln!(" --> in thread_idle");
+    debug!("in thread_idle");
     loop {
         x86::enable_irqs();
         x86::pause();
@@ -186,6 +277,7 @@ fn schedule_inner() {
     let from_buf: *mut JmpBuf;
     let to_buf: *const JmpBuf;
     let to_stack: usize;
+    let to_table: Option<PhysicalPage>;
 
     {
         let to: ThreadArc;
@@ -196,6 +288,10 @@ fn schedule_inner() {
             None => return,
         };
 
+        for th in WOKEN.lock().drain(..) {
+            threads.set_runnable(th);
+        }
+
         let to_opt = threads.next_runnable();
         from = threads.running.clone();
 
@@ -223,6 +319,7 @@ fn schedule_inner() {
 
         to_buf = &to.read().context as *const JmpBuf;
         to_stack = to.read().stack.stack_ptr();
+        to_table = to.read().page_table;
         from_buf = from
             .map(|th| &mut th.write().context as *mut JmpBuf)
             .unwrap_or(ptr::null_mut());
@@ -230,6 +327,12 @@ fn schedule_inner() {
 
     unsafe {
         x86::set_kernel_stack(to_stack);
+        if let Some(root) = to_table {
+            let table = PageTable(root);
+            if !table.is_current() {
+                table.activate();
+            }
+        }
         switch(to_buf, from_buf);
     }
 }
@@ -240,10 +343,219 @@ pub fn schedule() {
     x86::enable_irqs();
 }
 
+/// Stop the running thread until something calls `wake` on it. This has to
+/// be called with interrupts disabled, after the thread has been put
+/// somewhere `wake` will find it, so a wakeup can't be missed in between.
+fn block() {
+    if let Some(thread) = running() {
+        thread.write().state = State::Stopped;
+        schedule_inner();
+    }
+}
+
+/// Make a thread stopped by `block` runnable again. Waking a thread that
+/// is not stopped does nothing, so it is safe to do more than once.
+pub fn wake(thread: ThreadArc) {
+    x86::without_interrupts(|| {
+        {
+            let mut th = thread.write();
+            if th.state != State::Stopped {
+                return;
+            }
+            th.state = State::Running;
+        }
+        match THREADS.try_write() {
+            Some(mut threads) => threads.set_runnable(thread),
+            None => WOKEN.lock().push(thread),
+        }
+    })
+}
+
+/// A list of threads waiting for something to happen.
+pub struct WaitQueue {
+    waiters: Mutex<Vec<ThreadArc>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Block the running thread until the queue is woken. Call this with
+    /// interrupts disabled, after checking the condition being waited for
+    /// and finding it false. Interrupts are still disabled when this
+    /// returns. The wakeup may be spurious, so check the condition again.
+    pub fn wait(&self) {
+        let thread = match running() {
+            Some(thread) => thread,
+            None => {
+                // Nothing to block before the scheduler starts, so just
+                // wait for the next interrupt.
+                x86::enable_irqs();
+                x86::pause();
+                x86::disable_irqs();
+                return;
+            }
+        };
+        self.waiters.lock().push(thread);
+        block();
+    }
+
+    pub fn wake_all(&self) {
+        let waiters =
+            x86::without_interrupts(|| mem::take(&mut *self.waiters.lock()));
+        for thread in waiters {
+            wake(thread);
+        }
+    }
+}
+
+/// The timer callback for `sleep`. `data` is a reference to the sleeping
+/// thread, leaked by Arc::into_raw.
+fn wake_sleeper(data: usize) {
+    let thread = unsafe { Arc::from_raw(data as *const RwLock<Thread>) };
+    wake(thread);
+}
+
+/// Stop the running thread for at least `duration`. It wakes on the first
+/// timer tick after that, so the resolution is one tick.
+pub fn sleep(duration: Duration) {
+    let deadline = time::now().saturating_add(duration);
+    let thread = match running() {
+        Some(thread) => thread,
+        None => {
+            // Nothing to stop before the scheduler starts
+            while time::now() < deadline {
+                x86::pause();
+            }
+            return;
+        }
+    };
+    x86::without_interrupts(|| {
+        let data = Arc::into_raw(thread.clone()) as usize;
+        timer::at(deadline, "sleep", wake_sleeper, data);
+        // The timer can't fire until block() switches away, since
+        // interrupts are off, so the wakeup can't be missed.
+        while time::now() < deadline {
+            block();
+        }
+        // If block() came back without switching, the timer may not have
+        // fired yet; it does nothing to a running thread when it does.
+        thread.write().state = State::Running;
+    });
+}
+
+/// Switch threads on every tick of the timer on IRQ 0.
+pub fn start_preemption() {
+    interrupt::register_irq(0, "scheduler", |_, _| schedule(), 0)
+        .expect("failed to register the scheduler");
+}
+
 pub fn id() -> usize {
-    running().map(|th| {
-        th.read().id
-    }).unwrap_or(0)
+    running().map(|th| th.read().id).unwrap_or(0)
+}
+
+/// The process of the running thread, or None for a kernel thread
+pub fn process() -> Option<ProcessArc> {
+    running().and_then(|th| th.read().process.clone())
+}
+
+// The try_ functions below never wait on a lock, for the debugger stub,
+// which can stop the kernel while any of them is held.
+
+/// The running thread's id, or None if it is locked.
+pub fn try_id() -> Option<usize> {
+    let threads = THREADS.try_read()?;
+    match threads.running.as_ref() {
+        Some(thread) => Some(thread.try_read()?.id),
+        None => Some(0),
+    }
+}
+
+/// Look up a thread by id. The idle thread is id 0.
+pub fn try_get(id: usize) -> Option<ThreadArc> {
+    let threads = THREADS.try_read()?;
+    if id == 0 {
+        return Some(threads.idle());
+    }
+    threads.get(id)
+}
+
+/// The thread whose stack guard page `addr` is in, for reporting stack
+/// overflows. None if there isn't one or the thread set is locked.
+pub fn try_stack_overflowed(addr: usize) -> Option<usize> {
+    let mut found = None;
+    try_for_each(|thread| {
+        if thread.stack.guard_contains(addr) {
+            found = Some(thread.id);
+        }
+    });
+    found
+}
+
+/// Call `f` on the idle thread and then every other thread, skipping the
+/// ones that are locked. Returns false if the thread set is locked.
+pub fn try_for_each(mut f: impl FnMut(&Thread)) -> bool {
+    let threads = match THREADS.try_read() {
+        Some(threads) => threads,
+        None => return false,
+    };
+    for thread in Some(&threads.idle)
+        .into_iter()
+        .chain(threads.threads.values())
+    {
+        if let Some(thread) = thread.try_read() {
+            f(&thread);
+        }
+    }
+    true
+}
+
+pub fn register_commands() {
+    shell::register(Command {
+        name: "sleep",
+        usage: "sleep <milliseconds>",
+        help: "sleep the shell thread",
+        run: |args| {
+            let millis =
+                shell::parse_number(args.first().ok_or("missing time")?)?;
+            sleep(Duration::from_millis(millis as u64));
+            Ok(())
+        },
+    });
+    shell::register(Command {
+        name: "threads",
+        usage: "threads",
+        help: "list threads",
+        run: |_| {
+            let current = id();
+            let threads: Vec<ThreadArc> =
+                THREADS.read().threads.values().cloned().collect();
+            println!(
+                "{:>6} {:<8} {:>7} {:>18}",
+                "id", "state", "process", "ip"
+            );
+            for thread in threads {
+                let th = thread.read();
+                let marker = if th.id == current { '*' } else { ' ' };
+                let process = match &th.process {
+                    Some(process) => format!("{}", process.read().id()),
+                    None => String::from("-"),
+                };
+                println!(
+                    "{}{:>5} {:<8} {:>7} {:>#18x}",
+                    marker,
+                    th.id,
+                    format!("{:?}", th.state),
+                    process,
+                    th.context.ip
+                );
+            }
+            Ok(())
+        },
+    });
 }
 
 unsafe fn switch(to: *const JmpBuf, from: *mut JmpBuf) {
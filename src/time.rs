@@ -0,0 +1,205 @@
+//! The monotonic clock. Timer ticks on IRQ 0 are always counted; when the
+//! CPU has a TSC it is calibrated against the PIT at boot and used for
+//! nanosecond resolution, otherwise the time comes from the tick count.
+//!
+//! Ticks come from the PIT whether the PIC or the I/O APIC delivers them.
+
+use crate::shell::{self, Command};
+use crate::{interrupt, x86};
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+pub use core::time::Duration;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// How long to count TSC cycles against the PIT for. Longer is more
+/// accurate, but the PIT can't count much past 50ms.
+const CALIBRATION_MILLIS: usize = 50;
+
+/// Timer interrupts since init
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// The TSC's rate, or 0 if the tick count is the clock
+static TSC_HERTZ: AtomicU64 = AtomicU64::new(0);
+
+/// The TSC at init, which is time zero
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// The latest time handed out, so time never goes backwards even if the
+/// clock source does
+static LAST: AtomicU64 = AtomicU64::new(0);
+
+/// A point in time, in nanoseconds since the clock started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const ZERO: Instant = Instant(0);
+
+    pub fn from_nanos(nanos: u64) -> Self {
+        Instant(nanos)
+    }
+
+    pub fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// The time from `earlier` to this instant, or zero if `earlier` is
+    /// actually later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(self) -> Duration {
+        now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        let nanos = duration.as_nanos();
+        if nanos > u64::MAX as u128 {
+            return None;
+        }
+        self.0.checked_add(nanos as u64).map(Instant)
+    }
+
+    /// `self + duration`, or the end of time if that overflows. For
+    /// deadlines, which are then never reached.
+    pub fn saturating_add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap_or(Instant(u64::MAX))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs)
+            .expect("overflow adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Display for Instant {
+    /// Seconds and microseconds since boot
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let micros = self.0 / 1000;
+        write!(f, "{}.{:06}", micros / 1_000_000, micros % 1_000_000)
+    }
+}
+
+fn tick(_: &mut x86::InterruptFrame, _: usize) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count TSC cycles over CALIBRATION_MILLIS of the PIT.
+fn calibrate_tsc() -> Option<u64> {
+    let counts = x86::PIT_HERTZ * CALIBRATION_MILLIS / 1000;
+    let (start, end, finished) = x86::without_interrupts(|| {
+        let start = x86::read_tsc();
+        let finished = x86::pit_wait(counts as u16);
+        (start, x86::read_tsc(), finished)
+    });
+    if !finished || end <= start {
+        return None;
+    }
+    Some((end - start) * 1000 / CALIBRATION_MILLIS as u64)
+}
+
+/// Start counting ticks and calibrate the TSC. This has to be called
+/// after x86::timer_init, and before anything else is registered on IRQ 0
+/// so the count is up to date when the other handlers run.
+pub fn init() {
+    if x86::has_tsc() {
+        match calibrate_tsc() {
+            Some(hertz) => {
+                if !x86::has_invariant_tsc() {
+                    warn!("the TSC may change rate with the CPU clock");
+                }
+                TSC_BASE.store(x86::read_tsc(), Ordering::Relaxed);
+                TSC_HERTZ.store(hertz, Ordering::Relaxed);
+                info!(
+                    "TSC runs at {}.{:03} MHz",
+                    hertz / 1_000_000,
+                    hertz / 1000 % 1000
+                );
+            }
+            None => warn!("couldn't calibrate the TSC against the PIT"),
+        }
+    }
+    if tsc_hertz().is_none() {
+        info!("clock runs on the {} Hz timer tick", x86::timer_hertz());
+    }
+    interrupt::register_irq(0, "clock", tick, 0)
+        .expect("failed to register the clock tick");
+}
+
+/// Timer ticks since init
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The calibrated TSC rate, if the TSC is the clock
+pub fn tsc_hertz() -> Option<u64> {
+    match TSC_HERTZ.load(Ordering::Relaxed) {
+        0 => None,
+        hertz => Some(hertz),
+    }
+}
+
+fn read_clock() -> u64 {
+    match tsc_hertz() {
+        Some(hertz) => {
+            let cycles = x86::read_tsc()
+                .saturating_sub(TSC_BASE.load(Ordering::Relaxed));
+            (cycles as u128 * NANOS_PER_SECOND as u128 / hertz as u128) as u64
+        }
+        None => match x86::timer_hertz() {
+            0 => 0,
+            hertz => ticks() as u64 * NANOS_PER_SECOND / hertz as u64,
+        },
+    }
+}
+
+/// The current time. Never less than any earlier result.
+pub fn now() -> Instant {
+    let nanos = read_clock();
+    let last = LAST.fetch_max(nanos, Ordering::Relaxed);
+    Instant(nanos.max(last))
+}
+
+/// Time since the clock started
+pub fn uptime() -> Duration {
+    now().duration_since(Instant::ZERO)
+}
+
+pub fn register_commands() {
+    shell::register(Command {
+        name: "uptime",
+        usage: "uptime",
+        help: "show the time since boot and the clock source",
+        run: |_| {
+            println!("up {} seconds, {} ticks", now(), ticks());
+            match tsc_hertz() {
+                Some(hertz) => {
+                    println!("clock: TSC at {} Hz", hertz);
+                }
+                None => {
+                    println!("clock: {} Hz timer tick", x86::timer_hertz());
+                }
+            }
+            Ok(())
+        },
+    });
+}
@@ -0,0 +1,206 @@
+//! Kernel timers: callbacks that run once at a deadline or periodically.
+//! Pending timers are kept in a heap ordered by deadline, and the ones
+//! that are due run from the timer tick on IRQ 0, so they fire up to one
+//! tick late.
+
+use crate::shell::{self, Command};
+use crate::sync::Mutex;
+use crate::time::{self, Duration, Instant};
+use crate::{interrupt, x86};
+use alloc::collections::BinaryHeap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::sync::atomic::{self, AtomicUsize};
+
+/// A timer callback, called from the timer interrupt with the data the
+/// timer was created with. It must not block.
+pub type Callback = fn(data: usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(usize);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    deadline: Instant,
+    id: usize,
+    /// Periodic timers are put back this far after each deadline
+    period: Option<Duration>,
+    name: &'static str,
+    callback: Callback,
+    data: usize,
+}
+
+// BinaryHeap is a max-heap, so timers compare backwards to put the
+// earliest deadline on top. Timers with the same deadline run in the order
+// they were added.
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deadline, other.id).cmp(&(self.deadline, self.id))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Timer {}
+
+lazy_static! {
+    /// Only locked with interrupts disabled
+    static ref TIMERS: Mutex<BinaryHeap<Timer>> = Mutex::new(BinaryHeap::new());
+}
+
+static NEXT_TIMER_ID: AtomicUsize = AtomicUsize::new(1);
+
+fn add(
+    deadline: Instant,
+    period: Option<Duration>,
+    name: &'static str,
+    callback: Callback,
+    data: usize,
+) -> TimerId {
+    let id = NEXT_TIMER_ID.fetch_add(1, atomic::Ordering::Relaxed);
+    let timer = Timer {
+        deadline,
+        id,
+        period,
+        name,
+        callback,
+        data,
+    };
+    x86::without_interrupts(|| TIMERS.lock().push(timer));
+    TimerId(id)
+}
+
+/// Call `callback` once, at the first tick at or after `deadline`.
+pub fn at(
+    deadline: Instant,
+    name: &'static str,
+    callback: Callback,
+    data: usize,
+) -> TimerId {
+    add(deadline, None, name, callback, data)
+}
+
+/// Call `callback` once, `delay` from now.
+pub fn after(
+    delay: Duration,
+    name: &'static str,
+    callback: Callback,
+    data: usize,
+) -> TimerId {
+    at(time::now().saturating_add(delay), name, callback, data)
+}
+
+/// Call `callback` every `period`, starting one period from now, until
+/// the timer is cancelled. The period can't be zero.
+pub fn every(
+    period: Duration,
+    name: &'static str,
+    callback: Callback,
+    data: usize,
+) -> TimerId {
+    assert!(period.as_nanos() > 0, "timer period must not be zero");
+    add(
+        time::now().saturating_add(period),
+        Some(period),
+        name,
+        callback,
+        data,
+    )
+}
+
+/// Stop a timer. Returns false if it had already fired, or was cancelled
+/// before.
+pub fn cancel(id: TimerId) -> bool {
+    x86::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let before = timers.len();
+        let kept: Vec<Timer> =
+            timers.drain().filter(|timer| timer.id != id.0).collect();
+        let cancelled = kept.len() != before;
+        *timers = BinaryHeap::from(kept);
+        cancelled
+    })
+}
+
+/// Take the earliest timer if it's due, putting a periodic one back for
+/// its next deadline.
+fn next_due(now: Instant) -> Option<Timer> {
+    x86::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        if timers.peek()?.deadline > now {
+            return None;
+        }
+        let timer = timers.pop()?;
+        if let Some(period) = timer.period {
+            let mut next = timer;
+            next.deadline = timer.deadline.saturating_add(period);
+            // If ticks were missed, skip the deadlines that went by rather
+            // than firing for each of them at once
+            if next.deadline <= now {
+                next.deadline = now.saturating_add(period);
+            }
+            timers.push(next);
+        }
+        Some(timer)
+    })
+}
+
+/// Run every timer that is due. The lock isn't held while a callback
+/// runs, so callbacks can add and cancel timers.
+fn service(_: &mut x86::InterruptFrame, _: usize) {
+    let now = time::now();
+    while let Some(timer) = next_due(now) {
+        (timer.callback)(timer.data);
+    }
+}
+
+/// Start servicing timers. Call this after time::init, so the clock has
+/// ticked before the timers are checked, and before the scheduler is
+/// registered on IRQ 0, so threads the timers wake can run on this tick.
+pub fn init() {
+    interrupt::register_irq(0, "timers", service, 0)
+        .expect("failed to register the timer service");
+}
+
+pub fn register_commands() {
+    shell::register(Command {
+        name: "timers",
+        usage: "timers",
+        help: "list pending timers",
+        run: |_| {
+            let mut timers = x86::without_interrupts(|| {
+                TIMERS.lock().iter().copied().collect::<Vec<Timer>>()
+            });
+            timers.sort_by_key(|timer| (timer.deadline, timer.id));
+            let now = time::now();
+            println!(
+                "{:>6} {:<16} {:>10} {:>10}",
+                "id", "name", "due (ms)", "every"
+            );
+            for timer in timers {
+                let due = timer.deadline.duration_since(now).as_millis();
+                let period = match timer.period {
+                    Some(period) => format!("{}", period.as_millis()),
+                    None => String::from("-"),
+                };
+                println!(
+                    "{:>6} {:<16} {:>10} {:>10}",
+                    timer.id, timer.name, due, period
+                );
+            }
+            Ok(())
+        },
+    });
+}
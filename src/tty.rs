@@ -0,0 +1,340 @@
+//! The TTY line discipline, which sits between a raw byte device and the
+//! thread reading from it. In canonical mode input is collected into lines
+//! with echo and erase; in raw mode bytes are passed straight through.
+//! Either way ^C and ^Z are turned into errors for the reader, which is the
+//! foreground reader for job control purposes.
+
+use crate::serial;
+use crate::sync::Mutex;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A device a TTY can run on: something to read input bytes from and
+/// write output to.
+pub trait CharDevice: Sync {
+    /// Wait for the next byte of input.
+    fn read_byte(&self) -> u8;
+    fn write(&self, bytes: &[u8]);
+}
+
+bitflags! {
+    pub struct TtyFlags: u32 {
+        /// Collect input into lines, with erase, kill and ^D
+        const CANONICAL = 1 << 0;
+        /// Echo input back to the device
+        const ECHO = 1 << 1;
+        /// Turn ^C and ^Z into Interrupted and Suspended
+        const SIGNALS = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyError {
+    /// ^C was typed
+    Interrupted,
+    /// ^Z was typed
+    Suspended,
+}
+
+impl fmt::Display for TtyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            TtyError::Interrupted => "interrupted",
+            TtyError::Suspended => "suspended",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A key press, with ANSI escape sequences decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Byte(u8),
+    Up,
+    Down,
+    Right,
+    Left,
+    Home,
+    End,
+    Delete,
+}
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_U: u8 = 0x15;
+const CTRL_Z: u8 = 0x1A;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const ESCAPE: u8 = 0x1B;
+
+/// Where the decoder is in an escape sequence
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /// Saw ESC
+    Start,
+    /// Saw ESC [ or ESC O, and any digits since
+    Sequence(u8),
+}
+
+struct TtyState {
+    flags: TtyFlags,
+    /// The line being edited in canonical mode
+    line: Vec<u8>,
+    /// Input ready to be read
+    ready: VecDeque<u8>,
+    /// ^D on an empty line, which reads as end of file
+    eof: bool,
+    escape: Escape,
+}
+
+impl TtyState {
+    /// Feed a byte to the escape sequence decoder. Returns a key once one
+    /// is complete. ESC followed by anything but [ or O is taken as an
+    /// alt-modified key, and the ESC is dropped.
+    fn decode(&mut self, byte: u8) -> Option<Key> {
+        match self.escape {
+            Escape::None if byte == ESCAPE => {
+                self.escape = Escape::Start;
+                None
+            }
+            Escape::None => Some(Key::Byte(byte)),
+            Escape::Start => match byte {
+                b'[' | b'O' => {
+                    self.escape = Escape::Sequence(0);
+                    None
+                }
+                _ => {
+                    self.escape = Escape::None;
+                    Some(Key::Byte(byte))
+                }
+            },
+            Escape::Sequence(param) => {
+                if byte.is_ascii_digit() {
+                    // Long numbers stick at 255, which no key uses
+                    let param =
+                        param.saturating_mul(10).saturating_add(byte - b'0');
+                    self.escape = Escape::Sequence(param);
+                    return None;
+                }
+                self.escape = Escape::None;
+                match (byte, param) {
+                    (b'A', _) => Some(Key::Up),
+                    (b'B', _) => Some(Key::Down),
+                    (b'C', _) => Some(Key::Right),
+                    (b'D', _) => Some(Key::Left),
+                    (b'H', _) | (b'~', 1) | (b'~', 7) => Some(Key::Home),
+                    (b'F', _) | (b'~', 4) | (b'~', 8) => Some(Key::End),
+                    (b'~', 3) => Some(Key::Delete),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// Hand out up to `buf.len()` bytes of ready input.
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.ready.len());
+        for (out, byte) in buf.iter_mut().zip(self.ready.drain(..count)) {
+            *out = byte;
+        }
+        count
+    }
+}
+
+pub struct Tty {
+    device: &'static dyn CharDevice,
+    state: Mutex<TtyState>,
+}
+
+impl Tty {
+    pub fn new(device: &'static dyn CharDevice) -> Self {
+        Self {
+            device,
+            state: Mutex::new(TtyState {
+                flags: TtyFlags::CANONICAL | TtyFlags::ECHO | TtyFlags::SIGNALS,
+                line: Vec::new(),
+                ready: VecDeque::new(),
+                eof: false,
+                escape: Escape::None,
+            }),
+        }
+    }
+
+    pub fn flags(&self) -> TtyFlags {
+        self.state.lock().flags
+    }
+
+    /// Change modes. Leaving canonical mode makes the partial line
+    /// readable.
+    pub fn set_flags(&self, flags: TtyFlags) {
+        let mut state = self.state.lock();
+        if !flags.contains(TtyFlags::CANONICAL) {
+            let line = core::mem::take(&mut state.line);
+            state.ready.extend(line);
+        }
+        state.flags = flags;
+    }
+
+    pub fn write(&self, bytes: &[u8]) {
+        self.device.write(bytes);
+    }
+
+    fn echo(&self, flags: TtyFlags, bytes: &[u8]) {
+        if flags.contains(TtyFlags::ECHO) {
+            self.device.write(bytes);
+        }
+    }
+
+    /// Check for ^C and ^Z, throwing away unread input if one was typed.
+    fn signal(&self, flags: TtyFlags, byte: u8) -> Result<(), TtyError> {
+        if !flags.contains(TtyFlags::SIGNALS) {
+            return Ok(());
+        }
+        let (error, echo): (_, &[u8]) = match byte {
+            CTRL_C => (TtyError::Interrupted, b"^C\r\n"),
+            CTRL_Z => (TtyError::Suspended, b"^Z\r\n"),
+            _ => return Ok(()),
+        };
+        {
+            let mut state = self.state.lock();
+            state.line.clear();
+            state.ready.clear();
+        }
+        self.echo(flags, echo);
+        Err(error)
+    }
+
+    /// Process one byte of input from the device.
+    fn input(&self, byte: u8) -> Result<(), TtyError> {
+        let flags = self.flags();
+        self.signal(flags, byte)?;
+
+        if !flags.contains(TtyFlags::CANONICAL) {
+            self.state.lock().ready.push_back(byte);
+            self.echo(flags, &[byte]);
+            return Ok(());
+        }
+
+        let mut state = self.state.lock();
+        let key = match state.decode(byte) {
+            Some(Key::Byte(byte)) => byte,
+            // There's no line editing yet, so the other keys are dropped
+            // rather than letting their escape sequences into the line.
+            _ => return Ok(()),
+        };
+        match key {
+            b'\r' | b'\n' => {
+                let mut line = core::mem::take(&mut state.line);
+                line.push(b'\n');
+                state.ready.extend(line);
+                drop(state);
+                self.echo(flags, b"\r\n");
+            }
+            CTRL_D => {
+                if state.line.is_empty() {
+                    state.eof = true;
+                } else {
+                    let line = core::mem::take(&mut state.line);
+                    state.ready.extend(line);
+                }
+            }
+            BACKSPACE | DELETE => {
+                if state.line.pop().is_some() {
+                    drop(state);
+                    self.echo(flags, b"\x08 \x08");
+                }
+            }
+            CTRL_U => {
+                let count = state.line.len();
+                state.line.clear();
+                drop(state);
+                for _ in 0..count {
+                    self.echo(flags, b"\x08 \x08");
+                }
+            }
+            byte if byte >= 0x20 => {
+                state.line.push(byte);
+                drop(state);
+                self.echo(flags, &[byte]);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Read into `buf`, waiting until there is something to read. In
+    /// canonical mode that means a whole line, and no more than one line
+    /// is returned. Returns 0 at end of file, which is ^D on an empty line.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            {
+                let mut state = self.state.lock();
+                if state.eof {
+                    state.eof = false;
+                    return Ok(0);
+                }
+                let canonical = state.flags.contains(TtyFlags::CANONICAL);
+                let newline = state.ready.iter().position(|&b| b == b'\n');
+                match newline {
+                    Some(end) if canonical => {
+                        let len = buf.len().min(end + 1);
+                        return Ok(state.take(&mut buf[..len]));
+                    }
+                    _ if !state.ready.is_empty() => {
+                        return Ok(state.take(buf));
+                    }
+                    _ => {}
+                }
+            }
+            self.input(self.device.read_byte())?;
+        }
+    }
+
+    /// Read a line into `line`, newline included. Returns the number of
+    /// bytes read, which is 0 at end of file.
+    pub fn read_line(&self, line: &mut String) -> Result<usize, TtyError> {
+        let mut bytes = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let count = self.read(&mut buf)?;
+            bytes.extend_from_slice(&buf[..count]);
+            if count == 0 || bytes.last() == Some(&b'\n') {
+                break;
+            }
+        }
+        line.push_str(&String::from_utf8_lossy(&bytes));
+        Ok(bytes.len())
+    }
+
+    /// Read one key, decoding escape sequences, for raw mode programs that
+    /// want arrow keys. Ready input that has already been through the line
+    /// discipline is returned a byte at a time.
+    pub fn read_key(&self) -> Result<Key, TtyError> {
+        loop {
+            if let Some(byte) = self.state.lock().ready.pop_front() {
+                return Ok(Key::Byte(byte));
+            }
+            let byte = self.device.read_byte();
+            let flags = self.flags();
+            self.signal(flags, byte)?;
+            if let Some(key) = self.state.lock().decode(byte) {
+                return Ok(key);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONSOLE: Tty = Tty::new(serial::console());
+}
+
+/// The TTY on the serial console
+pub fn console() -> &'static Tty {
+    &CONSOLE
+}
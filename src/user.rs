@@ -0,0 +1,155 @@
+use crate::memory::{
+    lower_half_end, PageFlags, PageTable, VirtualAddress, PAGE_SIZE,
+};
+use crate::util::round_down;
+use crate::x86;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UserCopyError {
+    /// The range is not entirely inside the user half of the address space
+    NotUserAddress,
+    /// Some page in the range is mapped without the needed permissions
+    PermissionDenied,
+    /// The copy faulted part way through
+    Fault,
+}
+
+impl fmt::Display for UserCopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            UserCopyError::NotUserAddress => "address is not in user memory",
+            UserCopyError::PermissionDenied => "permission denied",
+            UserCopyError::Fault => "fault accessing user memory",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// A pointer into user memory. The kernel never dereferences these
+/// directly, all access goes through `copy_from_user` and `copy_to_user` so
+/// a bad pointer from a syscall becomes an error instead of a panic.
+pub struct UserPtr<T> {
+    addr: VirtualAddress,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> fmt::Debug for UserPtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UserPtr({:#x})", self.addr.0)
+    }
+}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn new(addr: usize) -> Self {
+        Self {
+            addr: VirtualAddress(addr),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn addr(self) -> VirtualAddress {
+        self.addr
+    }
+
+    pub fn is_null(self) -> bool {
+        self.addr.0 == 0
+    }
+
+    pub fn add(self, count: usize) -> Self {
+        Self::new(self.addr.0.wrapping_add(count * size_of::<T>()))
+    }
+
+    pub fn read(self) -> Result<T, UserCopyError> {
+        let mut v = MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                v.as_mut_ptr() as *mut u8,
+                size_of::<T>(),
+            )
+        };
+        copy_from_user(bytes, self.addr)?;
+        Ok(unsafe { v.assume_init() })
+    }
+
+    pub fn write(self, v: T) -> Result<(), UserCopyError> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &v as *const T as *const u8,
+                size_of::<T>(),
+            )
+        };
+        copy_to_user(self.addr, bytes)
+    }
+}
+
+/// Check that `len` bytes at `addr` are in the user half and mapped with
+/// the permissions the copy needs. Pages that are not present are let
+/// through; touching them goes through the page fault handler like any
+/// other user access, and fails cleanly via the exception table if the
+/// handler can't resolve it.
+fn check_user_range(
+    addr: VirtualAddress,
+    len: usize,
+    write: bool,
+) -> Result<(), UserCopyError> {
+    let end = addr
+        .0
+        .checked_add(len)
+        .ok_or(UserCopyError::NotUserAddress)?;
+    if addr.is_higher_half() || end > lower_half_end() {
+        return Err(UserCopyError::NotUserAddress);
+    }
+
+    let mut required = PageFlags::USERMODE;
+    if write {
+        required |= PageFlags::WRITEABLE;
+    }
+
+    let table = PageTable::current();
+    for page in (round_down(addr.0, PAGE_SIZE)..end).step_by(PAGE_SIZE) {
+        if let Some((_, flags)) = table.translate(VirtualAddress(page)) {
+            if !flags.contains(required) {
+                return Err(UserCopyError::PermissionDenied);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn copy_from_user(
+    dst: &mut [u8],
+    src: VirtualAddress,
+) -> Result<(), UserCopyError> {
+    check_user_range(src, dst.len(), false)?;
+    let left = unsafe {
+        x86::copy_user(dst.as_mut_ptr(), src.0 as *const u8, dst.len())
+    };
+    if left != 0 {
+        return Err(UserCopyError::Fault);
+    }
+    Ok(())
+}
+
+pub fn copy_to_user(
+    dst: VirtualAddress,
+    src: &[u8],
+) -> Result<(), UserCopyError> {
+    check_user_range(dst, src.len(), true)?;
+    let left =
+        unsafe { x86::copy_user(dst.0 as *mut u8, src.as_ptr(), src.len()) };
+    if left != 0 {
+        return Err(UserCopyError::Fault);
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! A minimal writer for the VGA text mode screen, which scrolls up as
+//! lines are added at the bottom.
+
+use crate::memory::PhysicalAddress;
+use crate::sync::Mutex;
+use crate::x86;
+use core::fmt::{self, Write};
+use core::ptr;
+
+const TEXT_BUFFER: PhysicalAddress = PhysicalAddress(0xB8000);
+const WIDTH: usize = 80;
+const HEIGHT: usize = 25;
+
+/// Light grey on black
+const DEFAULT_COLOR: u8 = 0x07;
+
+pub struct Writer {
+    column: usize,
+    color: u8,
+}
+
+impl Writer {
+    const fn new() -> Self {
+        Self {
+            column: 0,
+            color: DEFAULT_COLOR,
+        }
+    }
+
+    fn cell(row: usize, column: usize) -> *mut u16 {
+        let buffer = TEXT_BUFFER.direct_map() as *mut u16;
+        buffer.wrapping_add(row * WIDTH + column)
+    }
+
+    fn blank(&self) -> u16 {
+        (self.color as u16) << 8 | b' ' as u16
+    }
+
+    fn newline(&mut self) {
+        unsafe {
+            for row in 1..HEIGHT {
+                for column in 0..WIDTH {
+                    let cell = ptr::read_volatile(Self::cell(row, column));
+                    ptr::write_volatile(Self::cell(row - 1, column), cell);
+                }
+            }
+            for column in 0..WIDTH {
+                ptr::write_volatile(
+                    Self::cell(HEIGHT - 1, column),
+                    self.blank(),
+                );
+            }
+        }
+        self.column = 0;
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            byte => {
+                if self.column >= WIDTH {
+                    self.newline();
+                }
+                // Anything outside printable ASCII shows as a block
+                let byte = match byte {
+                    0x20..=0x7e => byte,
+                    _ => 0xFE,
+                };
+                let cell = (self.color as u16) << 8 | byte as u16;
+                unsafe {
+                    ptr::write_volatile(
+                        Self::cell(HEIGHT - 1, self.column),
+                        cell,
+                    )
+                };
+                self.column += 1;
+            }
+        }
+    }
+
+    pub fn set_color(&mut self, color: u8) {
+        self.color = color;
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+static WRITER: Mutex<Writer> = Mutex::new(Writer::new());
+
+/// Lock the screen. Interrupts are disabled while it is held, since the
+/// log writes here from interrupt handlers.
+pub fn with_writer<T>(f: impl FnOnce(&mut Writer) -> T) -> T {
+    x86::without_interrupts(|| f(&mut WRITER.lock()))
+}
+
+pub fn print(args: fmt::Arguments) {
+    with_writer(|writer| writer.write_fmt(args).unwrap());
+}
@@ -1,4 +1,6 @@
+use crate::memory::{self, VirtualAddress, PAGE_SIZE};
 use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 extern "C" {
     pub fn outb(port: u16, val: u8);
@@ -6,6 +8,7 @@ extern "C" {
 
     fn asm_enable_irqs();
     fn asm_disable_irqs();
+    fn asm_read_flags() -> usize;
 
     fn asm_break_point();
     fn asm_pause();
@@ -17,7 +20,23 @@ extern "C" {
     pub fn set_jump(buf: *mut JmpBuf) -> isize;
     pub fn long_jump(buf: *const JmpBuf, value: isize) -> !;
 
+    fn asm_read_cr0() -> usize;
     fn asm_read_cr2() -> usize;
+    fn asm_read_rbp() -> usize;
+    fn asm_read_cr3() -> usize;
+    fn asm_write_cr3(cr3: usize);
+    fn asm_read_cr4() -> usize;
+    fn asm_write_cr4(cr4: usize);
+    fn asm_invpcid(kind: usize, descriptor: *const [u64; 2]);
+    fn asm_invlpg(addr: usize);
+
+    fn asm_cpuid(leaf: u32, subleaf: u32, result: *mut CpuidResult);
+
+    fn asm_read_msr(msr: u32) -> usize;
+    fn asm_read_tsc() -> u64;
+    fn asm_write_msr(msr: u32, value: usize);
+
+    fn asm_copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
 
     fn asm_jmp_to_user(
         ip: usize,
@@ -28,6 +47,11 @@ extern "C" {
     );
 
     static mut kernel_stack: usize;
+    static mut interrupt_stack_table: [usize; 7];
+    static hhstack_guard_page: u8;
+
+    static _ex_table_start: ExceptionTableEntry;
+    static _ex_table_end: ExceptionTableEntry;
 }
 
 pub fn enable_interrupts() {
@@ -46,6 +70,24 @@ pub fn disable_irqs() {
     disable_interrupts();
 }
 
+pub const FLAG_TRAP: usize = 1 << 8;
+pub const FLAG_INTERRUPT: usize = 1 << 9;
+
+pub fn interrupts_enabled() -> bool {
+    unsafe { asm_read_flags() & FLAG_INTERRUPT != 0 }
+}
+
+/// Run `f` with interrupts disabled, then put them back the way they were.
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let enabled = interrupts_enabled();
+    disable_interrupts();
+    let result = f();
+    if enabled {
+        enable_interrupts();
+    }
+    result
+}
+
 pub fn break_point() {
     unsafe { asm_break_point() };
 }
@@ -62,6 +104,24 @@ pub fn kernel_end() -> usize {
     unsafe { asm_kernel_end() }
 }
 
+const KEYBOARD_COMMAND: u16 = 0x64;
+const KEYBOARD_RESET: u8 = 0xFE;
+const RESET_CONTROL: u16 = 0xCF9;
+const RESET_CONTROL_HARD: u8 = 0x06;
+
+/// Reset the machine through the keyboard controller, or the PCI reset
+/// control register if that doesn't work.
+pub fn reboot() -> ! {
+    disable_interrupts();
+    unsafe {
+        outb(KEYBOARD_COMMAND, KEYBOARD_RESET);
+        outb(RESET_CONTROL, RESET_CONTROL_HARD);
+    }
+    loop {
+        pause();
+    }
+}
+
 pub fn jmp_to_user(f: usize, sp: usize) {
     unsafe {
         asm_jmp_to_user(f, sp, 0, 0, 0);
@@ -72,6 +132,40 @@ pub unsafe fn set_kernel_stack(new_stack: usize) {
     *&mut kernel_stack = new_stack;
 }
 
+/// Copy `len` bytes that may be in user memory, returning how many bytes
+/// could not be copied because of a page fault.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    asm_copy_user(dst, src, len)
+}
+
+/// An entry in the exception table built by the assembly in user.asm. If an
+/// instruction at `ip` faults, execution can safely resume at `fixup`.
+#[repr(C)]
+#[derive(Debug)]
+struct ExceptionTableEntry {
+    ip: usize,
+    fixup: usize,
+}
+
+fn exception_table() -> &'static [ExceptionTableEntry] {
+    unsafe {
+        let start = &_ex_table_start as *const ExceptionTableEntry;
+        let end = &_ex_table_end as *const ExceptionTableEntry;
+        let len = (end as usize - start as usize)
+            / core::mem::size_of::<ExceptionTableEntry>();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// The address to resume at if the instruction at `ip` faults, if it is one
+/// that is expected to.
+pub fn exception_fixup(ip: usize) -> Option<usize> {
+    exception_table()
+        .iter()
+        .find(|entry| entry.ip == ip)
+        .map(|entry| entry.fixup)
+}
+
 bitflags! {
     pub struct FaultCode: u16 {
         const PRESENT  = 0x01;
@@ -113,6 +207,52 @@ impl fmt::Display for FaultCode {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// The error code pushed by #TS, #NP, #SS and #GP, naming the segment
+/// selector or IDT entry at fault
+#[derive(Debug, Clone, Copy)]
+pub struct SelectorError {
+    /// The fault happened while delivering an external interrupt
+    pub external: bool,
+    pub table: DescriptorTable,
+    pub index: u16,
+}
+
+impl SelectorError {
+    pub fn from_code(code: usize) -> Self {
+        let table = match (code >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        };
+        Self {
+            external: code & 1 != 0,
+            table,
+            index: ((code >> 3) & 0x1FFF) as u16,
+        }
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.index == 0 && self.table == DescriptorTable::Gdt {
+            write!(f, "no selector")?;
+        } else {
+            write!(f, "{:?} entry {}", self.table, self.index)?;
+        }
+        if self.external {
+            write!(f, ", external")?;
+        }
+        Ok(())
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct InterruptFrame {
@@ -160,10 +300,145 @@ impl JmpBuf {
     }
 }
 
+pub fn read_cr0() -> usize {
+    unsafe { asm_read_cr0() }
+}
+
 pub fn read_cr2() -> usize {
     unsafe { asm_read_cr2() }
 }
 
+/// The frame pointer of the function that calls this. It has to be
+/// inlined, or this would be the frame it reads.
+#[inline(always)]
+pub fn read_rbp() -> usize {
+    unsafe { asm_read_rbp() }
+}
+
+pub fn read_cr3() -> usize {
+    unsafe { asm_read_cr3() }
+}
+
+pub unsafe fn write_cr3(cr3: usize) {
+    asm_write_cr3(cr3);
+}
+
+pub fn read_cr4() -> usize {
+    unsafe { asm_read_cr4() }
+}
+
+pub unsafe fn write_cr4(cr4: usize) {
+    asm_write_cr4(cr4);
+}
+
+pub const CR4_LA57: usize = 1 << 12;
+pub const CR4_PCIDE: usize = 1 << 17;
+
+/// Set in a value written to CR3 to keep the TLB entries for the new PCID
+pub const CR3_NOFLUSH: usize = 1 << 63;
+pub const CR3_PCID_MASK: usize = 0xFFF;
+
+/// Invalidate the TLB entry for `addr` in address space `pcid`, which
+/// doesn't have to be the current one. Needs INVPCID support.
+pub fn invpcid_address(pcid: u16, addr: usize) {
+    let descriptor = [pcid as u64, addr as u64];
+    unsafe { asm_invpcid(0, &descriptor) };
+}
+
+/// Invalidate every non-global TLB entry for `pcid`. Needs INVPCID support.
+pub fn invpcid_context(pcid: u16) {
+    let descriptor = [pcid as u64, 0];
+    unsafe { asm_invpcid(1, &descriptor) };
+}
+
+/// Invalidate every TLB entry for every PCID, global ones included. Needs
+/// INVPCID support.
+pub fn invpcid_all() {
+    let descriptor = [0u64, 0];
+    unsafe { asm_invpcid(2, &descriptor) };
+}
+
+pub fn invlpg(addr: usize) {
+    unsafe { asm_invlpg(addr) };
+}
+
+pub fn flush_tlb() {
+    unsafe { write_cr3(read_cr3()) };
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let mut result = CpuidResult::default();
+    unsafe { asm_cpuid(leaf, subleaf, &mut result) };
+    result
+}
+
+fn cpuid_max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000, 0).eax
+}
+
+fn cpuid_max_leaf() -> u32 {
+    cpuid(0, 0).eax
+}
+
+/// Whether the CPU supports process-context identifiers (CPUID.01h:ECX[17])
+pub fn has_pcid() -> bool {
+    cpuid(1, 0).ecx & (1 << 17) != 0
+}
+
+/// Whether the CPU has the INVPCID instruction (CPUID.07h:EBX[10])
+pub fn has_invpcid() -> bool {
+    cpuid_max_leaf() >= 7 && cpuid(7, 0).ebx & (1 << 10) != 0
+}
+
+/// Whether the CPU supports 5-level paging (CPUID.07h:ECX[16])
+pub fn has_la57() -> bool {
+    cpuid_max_leaf() >= 7 && cpuid(7, 0).ecx & (1 << 16) != 0
+}
+
+/// Whether the CPU supports 1GiB pages in the PDPT (CPUID.80000001h:EDX[26])
+pub fn has_1g_pages() -> bool {
+    cpuid_max_extended_leaf() >= 0x8000_0001
+        && cpuid(0x8000_0001, 0).edx & (1 << 26) != 0
+}
+
+/// Whether the CPU has a time stamp counter (CPUID.01h:EDX[4])
+pub fn has_tsc() -> bool {
+    cpuid(1, 0).edx & (1 << 4) != 0
+}
+
+/// Whether the TSC runs at a constant rate in every power state
+/// (CPUID.80000007h:EDX[8])
+pub fn has_invariant_tsc() -> bool {
+    cpuid_max_extended_leaf() >= 0x8000_0007
+        && cpuid(0x8000_0007, 0).edx & (1 << 8) != 0
+}
+
+pub fn read_tsc() -> u64 {
+    unsafe { asm_read_tsc() }
+}
+
+/// Whether the CPU has a local APIC (CPUID.01h:EDX[9])
+pub fn has_apic() -> bool {
+    cpuid(1, 0).edx & (1 << 9) != 0
+}
+
+pub fn read_msr(msr: u32) -> usize {
+    unsafe { asm_read_msr(msr) }
+}
+
+pub unsafe fn write_msr(msr: u32, value: usize) {
+    asm_write_msr(msr, value)
+}
+
 const PRIMARY_PIC_COMMAND: u16 = 0x20;
 const PRIMARY_PIC_DATA: u16 = 0x21;
 const SECONDARY_PIC_COMMAND: u16 = 0xA0;
@@ -210,15 +485,44 @@ pub fn pic_init() {
     unmask_irq(2); // cascade irq
 }
 
+/// Mask every line on both PICs, once the APIC has taken over. They stay
+/// remapped by pic_init so a spurious interrupt from them can't land on an
+/// exception vector.
+pub fn pic_disable() {
+    unsafe {
+        outb(PRIMARY_PIC_DATA, 0xFF);
+        outb(SECONDARY_PIC_DATA, 0xFF);
+    }
+}
+
+/// The rate the PIT counts at
+pub const PIT_HERTZ: usize = 1_193_182;
+
 const TIMER_CH0: u16 = 0x40;
+const TIMER_CH2: u16 = 0x42;
 const TIMER_CMD: u16 = 0x43;
 
 const TIMER_CHANNEL_0: u8 = 0;
+const TIMER_CHANNEL_2: u8 = 0x80;
 const TIMER_ACCESS_HILO: u8 = 0x30;
+const TIMER_MODE_0: u8 = 0x00; // interrupt on terminal count
 const TIMER_MODE_3: u8 = 0x06; // square wave
 
+/// Channel 2's gate is bit 0 of this port, the speaker enable is bit 1
+/// and the channel's output can be read back on bit 5.
+const TIMER_CH2_CONTROL: u16 = 0x61;
+const TIMER_CH2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const TIMER_CH2_OUTPUT: u8 = 1 << 5;
+
+/// Give up on channel 2 after polling it this many times
+const TIMER_CH2_MAX_POLLS: usize = 100_000_000;
+
+static TIMER_HERTZ: AtomicUsize = AtomicUsize::new(0);
+
 pub fn timer_init(hertz: usize) {
-    let mut divisor = 1_193_182 / hertz;
+    TIMER_HERTZ.store(hertz, Ordering::Relaxed);
+    let mut divisor = PIT_HERTZ / hertz;
     if divisor > 65535 {
         // 0 represents 65536 and is the largest possible divisor,
         // giving 18.2Hz
@@ -235,6 +539,36 @@ pub fn timer_init(hertz: usize) {
     }
 }
 
+/// Busy-wait for `counts` PIT cycles on channel 2, which is free for
+/// this since nothing drives the speaker. Returns false if the channel
+/// never finished, as on machines without one.
+pub fn pit_wait(counts: u16) -> bool {
+    unsafe {
+        let control = inb(TIMER_CH2_CONTROL) & !SPEAKER_ENABLE;
+        outb(TIMER_CH2_CONTROL, control & !TIMER_CH2_GATE);
+        outb(
+            TIMER_CMD,
+            TIMER_CHANNEL_2 | TIMER_ACCESS_HILO | TIMER_MODE_0,
+        );
+        outb(TIMER_CH2, counts as u8);
+        outb(TIMER_CH2, (counts >> 8) as u8);
+        // Counting starts when the gate goes high
+        outb(TIMER_CH2_CONTROL, control | TIMER_CH2_GATE);
+
+        for _ in 0..TIMER_CH2_MAX_POLLS {
+            if inb(TIMER_CH2_CONTROL) & TIMER_CH2_OUTPUT != 0 {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// The rate timer_init set the PIT to, or 0 before then
+pub fn timer_hertz() -> usize {
+    TIMER_HERTZ.load(Ordering::Relaxed)
+}
+
 pub fn send_eoi(irq: usize) {
     if irq >= 8 {
         unsafe { outb(SECONDARY_PIC_COMMAND, 0x20) };
@@ -298,6 +632,9 @@ extern "C" {
 
     fn isr_syscall();
     fn isr_panic();
+    fn isr_spurious();
+
+    fn syscall_entry();
 }
 
 fn raw_set_idt_gate(irq: usize, handler: u64, flags: u64, cs: u64, ist: u64) {
@@ -313,8 +650,102 @@ fn raw_set_idt_gate(irq: usize, handler: u64, flags: u64, cs: u64, ist: u64) {
     gate[1] = func_high;
 }
 
+/// The vector user mode raises with int 0x80 to make a syscall
+pub const SYSCALL_VECTOR: usize = 0x80;
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+
+/// System call extensions, which enable SYSCALL and SYSRET
+const EFER_SCE: usize = 1 << 0;
+
+/// SYSCALL loads CS from here and SS from the next selector
+const STAR_KERNEL_BASE: usize = 0x08;
+/// SYSRET loads SS from 8 past here and CS from 16 past here
+const STAR_USER_BASE: usize = 0x10;
+
+/// Flags SYSCALL clears: interrupts until the entry code is on the kernel
+/// stack, and the trap, direction and alignment check flags, which user
+/// mode shouldn't be able to set for the kernel
+const SYSCALL_FLAG_MASK: usize = FLAG_INTERRUPT | 0x100 | 0x400 | 0x4_0000;
+
+/// Point SYSCALL at syscall_entry in syscall.asm.
+pub fn syscall_init() {
+    #[allow(clippy::fn_to_numeric_cast)]
+    let entry = syscall_entry as usize;
+    unsafe {
+        write_msr(IA32_STAR, STAR_USER_BASE << 48 | STAR_KERNEL_BASE << 32);
+        write_msr(IA32_LSTAR, entry);
+        write_msr(IA32_FMASK, SYSCALL_FLAG_MASK);
+        write_msr(IA32_EFER, read_msr(IA32_EFER) | EFER_SCE);
+    }
+}
+
+/// Interrupt stack table slots. A vector that uses one always switches to
+/// its stack, so it can be handled even if the kernel stack is unusable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ist {
+    DoubleFault = 1,
+    Nmi = 2,
+    MachineCheck = 3,
+    Debug = 4,
+}
+
+impl Ist {
+    const ALL: [Ist; 4] =
+        [Ist::DoubleFault, Ist::Nmi, Ist::MachineCheck, Ist::Debug];
+}
+
+const IST_STACK_SIZE: usize = 4 * PAGE_SIZE;
+
+/// An interrupt stack with an unmapped guard page below it, so it can't
+/// overflow silently either
+#[repr(C, align(4096))]
+struct IstStack {
+    guard: [u8; PAGE_SIZE],
+    stack: [u8; IST_STACK_SIZE],
+}
+
+const EMPTY_IST_STACK: IstStack = IstStack {
+    guard: [0; PAGE_SIZE],
+    stack: [0; IST_STACK_SIZE],
+};
+
+static mut IST_STACKS: [IstStack; Ist::ALL.len()] =
+    [EMPTY_IST_STACK; Ist::ALL.len()];
+
+/// Point the TSS at the interrupt stacks and guard them, and the boot
+/// stack too.
+fn ist_init() {
+    for (i, ist) in Ist::ALL.iter().enumerate() {
+        let stack = unsafe { &IST_STACKS[i] };
+        memory::set_guard_page(VirtualAddress(stack.guard.as_ptr() as usize));
+        let top = stack.stack.as_ptr() as usize + IST_STACK_SIZE;
+        unsafe { interrupt_stack_table[*ist as usize - 1] = top };
+    }
+    let boot_guard = unsafe { &hhstack_guard_page as *const u8 as usize };
+    memory::set_guard_page(VirtualAddress(boot_guard));
+}
+
+/// Run `vector` on an interrupt stack, or on the current stack if `ist`
+/// is None.
+pub fn set_ist(vector: usize, ist: Option<Ist>) {
+    let index = ist.map_or(0, |ist| ist as u64);
+    let gate = unsafe { &mut IDT[vector * 2] };
+    *gate = *gate & !(0x7 << 32) | index << 32;
+}
+
+/// Whether `vector` runs on an interrupt stack. Its handler has to return
+/// rather than switch threads, as the next one would reuse the stack.
+pub fn uses_ist(vector: usize) -> bool {
+    unsafe { IDT[vector * 2] >> 32 & 0x7 != 0 }
+}
+
 fn set_idt_gate(irq: usize, handler: unsafe extern "C" fn()) {
-    let rpl = 0; // 3 on syscall
+    // User mode can only use int on the syscall gate
+    let rpl = if irq == SYSCALL_VECTOR { 3 } else { 0 };
     let gdt_selector = 8;
     let gate_type = if irq >= 32 { 0x0F } else { 0x0E };
     let flags = 0x80 | rpl << 5 | gate_type;
@@ -375,6 +806,13 @@ pub fn idt_init() {
     set_idt_gate(46, irq14);
     set_idt_gate(47, irq15);
 
-    // set_idt_gate(128, isr_syscall);
+    set_idt_gate(SYSCALL_VECTOR, isr_syscall);
     set_idt_gate(130, isr_panic);
+    set_idt_gate(255, isr_spurious);
+
+    ist_init();
+    set_ist(1, Some(Ist::Debug));
+    set_ist(2, Some(Ist::Nmi));
+    set_ist(8, Some(Ist::DoubleFault));
+    set_ist(18, Some(Ist::MachineCheck));
 }
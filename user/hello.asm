@@ -0,0 +1,59 @@
+; vim: syntax=nasm :
+
+;; A test program for the ELF loader. It prints a greeting and its first
+;; argument, once through SYSCALL and once through int 0x80, then exits.
+
+%define SYS_EXIT 0
+%define SYS_WRITE 1
+%define STDOUT 1
+
+bits 64
+
+section .text
+
+global _start
+_start:
+    mov rax, SYS_WRITE
+    mov rdi, STDOUT
+    lea rsi, [rel greeting]
+    mov rdx, greeting_len
+    syscall
+
+    ;; rsp points at argc, with argv right above it
+    mov rsi, [rsp + 8]
+    mov rdi, rsi
+    call strlen
+    mov rdx, rax
+    mov rax, SYS_WRITE
+    mov rdi, STDOUT
+    int 0x80
+
+    mov rax, SYS_WRITE
+    mov rdi, STDOUT
+    lea rsi, [rel newline]
+    mov rdx, 1
+    int 0x80
+
+    mov rax, SYS_EXIT
+    xor edi, edi
+    syscall
+
+;; strlen(rdi) -> rax
+strlen:
+    xor eax, eax
+.loop:
+    cmp byte [rdi + rax], 0
+    je .done
+    inc rax
+    jmp .loop
+.done:
+    ret
+
+section .rodata
+
+greeting:
+    db "Hello from user mode, I am "
+greeting_len equ $ - greeting
+
+newline:
+    db 10